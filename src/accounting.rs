@@ -0,0 +1,127 @@
+// Every finished socks session yields a SessionRecord.
+//
+// The records are logged and summed up in Statistics. The most recent ones
+// are kept for display. Statistics are shared with the TUI thread.
+//
+use std::collections::VecDeque;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::{Arc,Mutex};
+use std::time::Duration;
+use address::Address;
+use country::code2country;
+
+// Number of records kept in Statistics::recent
+const MAX_RECENT: usize = 200;
+
+pub fn millis(dt: Duration) -> u64 {
+    (dt.as_secs()*1000)+(dt.subsec_millis() as u64)
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum Exit {
+    Unknown,
+    Direct,
+    Node(u8,Address)
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum CloseReason {
+    // Both directions have seen EOF
+    Closed,
+    Failed(String),
+    // Session has been dropped before completion
+    Aborted
+}
+
+#[derive(Debug,Clone)]
+pub struct SessionRecord {
+    pub client: Option<SocketAddr>,
+    pub target: String,
+    pub exit: Exit,
+    pub country: Option<usize>,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    pub connect_ms: Option<u64>,
+    pub duration: Duration,
+    pub reason: CloseReason
+}
+
+#[derive(Debug,Clone,Default)]
+pub struct Statistics {
+    pub sessions: u64,
+    pub failed: u64,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    pub recent: VecDeque<SessionRecord>
+}
+
+#[derive(Clone,Default)]
+pub struct Accounting {
+    stats: Arc<Mutex<Statistics>>
+}
+
+impl fmt::Display for Exit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Exit::Unknown => write!(f, "-"),
+            Exit::Direct => write!(f, "direct"),
+            Exit::Node(id,ref sa) => write!(f, "node {} ({})", id, sa)
+        }
+    }
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CloseReason::Closed => write!(f, "closed"),
+            CloseReason::Failed(ref e) => write!(f, "failed: {}", e),
+            CloseReason::Aborted => write!(f, "aborted")
+        }
+    }
+}
+
+impl fmt::Display for SessionRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let client = match self.client {
+            Some(sa) => sa.to_string(),
+            None => "-".to_string()
+        };
+        let country = match self.country {
+            Some(code) => code2country(code),
+            None => "--".to_string()
+        };
+        let connect = match self.connect_ms {
+            Some(ms) => format!("{}ms", ms),
+            None => "-".to_string()
+        };
+        write!(f, "{} -> {} via {} [{}] up {} down {} connect {} duration {}ms {}",
+               client, self.target, self.exit, country,
+               self.bytes_up, self.bytes_down, connect, millis(self.duration), self.reason)
+    }
+}
+
+impl Accounting {
+    pub fn new() -> Accounting {
+        Accounting::default()
+    }
+
+    pub fn record(&self, record: SessionRecord) {
+        info!("Session {}", record);
+        let mut stats = self.stats.lock().unwrap();
+        stats.sessions += 1;
+        if let CloseReason::Failed(_) = record.reason {
+            stats.failed += 1;
+        }
+        stats.bytes_up += record.bytes_up;
+        stats.bytes_down += record.bytes_down;
+        if stats.recent.len() >= MAX_RECENT {
+            stats.recent.pop_front();
+        }
+        stats.recent.push_back(record);
+    }
+
+    pub fn statistics(&self) -> Statistics {
+        self.stats.lock().unwrap().clone()
+    }
+}
@@ -0,0 +1,90 @@
+// Addresses of other nodes and proxies may be given as host:port.
+//
+// Hostnames, e.g. of dynamic dns, are resolved by the Connecter's resolver
+// and re-resolved periodically. The results are kept in the AddressBook,
+// so a changed address is used by new connections without restart.
+//
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr,SocketAddr};
+use std::str::FromStr;
+
+// Hostnames are resolved again after this time
+pub const REFRESH_INTERVAL_S: u64 = 300;
+
+#[derive(Debug,Clone,PartialEq,Eq,Hash)]
+pub enum Address {
+    Ip(SocketAddr),
+    Host(String,u16)
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Address::Ip(ref sa) => write!(f, "{}", sa),
+            Address::Host(ref host, port) => write!(f, "{}:{}", host, port)
+        }
+    }
+}
+
+impl Address {
+    pub fn parse(s: &str) -> Result<Address,String> {
+        let s = s.trim();
+        if let Ok(sa) = s.parse::<SocketAddr>() {
+            return Ok(Address::Ip(sa))
+        }
+        let mut parts = s.rsplitn(2, ':');
+        let port = parts.next().and_then(|p| u16::from_str(p).ok());
+        let host = parts.next().unwrap_or("");
+        let valid_host = host.len() > 0 && host.len() <= 253
+                         && host.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.');
+        match port {
+            Some(port) if valid_host => Ok(Address::Host(host.to_lowercase(), port)),
+            _ => Err(format!("<{}> is neither ip:port nor host:port", s))
+        }
+    }
+
+    pub fn host(&self) -> Option<&str> {
+        match *self {
+            Address::Host(ref host, _) => Some(host),
+            Address::Ip(_) => None
+        }
+    }
+}
+
+pub struct AddressBook {
+    resolved: RefCell<HashMap<String,Vec<IpAddr>>>
+}
+
+impl AddressBook {
+    pub fn new() -> AddressBook {
+        AddressBook {
+            resolved: RefCell::new(HashMap::new())
+        }
+    }
+
+    // None, if the hostname has not been resolved (yet)
+    pub fn lookup(&self, addr: &Address) -> Option<SocketAddr> {
+        match *addr {
+            Address::Ip(sa) => Some(sa),
+            Address::Host(ref host, port) => {
+                self.resolved.borrow().get(host)
+                    .and_then(|ips| ips.first())
+                    .map(|ip| SocketAddr::new(*ip, port))
+            }
+        }
+    }
+
+    // An empty result keeps the previous addresses
+    pub fn update(&self, host: &str, ips: Vec<IpAddr>) {
+        if ips.is_empty() {
+            return
+        }
+        let mut resolved = self.resolved.borrow_mut();
+        if resolved.get(host) != Some(&ips) {
+            info!("Address of {} is now {:?}", host, ips);
+            resolved.insert(host.to_string(), ips);
+        }
+    }
+}
@@ -0,0 +1,153 @@
+// Command line client for the control socket of uservpn-socks5.
+//
+// Sends one command and prints the answer as text, or as json with --json:
+//
+//      $ uservpnctl -s /tmp/uservpn.sock status
+//      $ uservpnctl sessions
+//      $ uservpnctl geoip www.example.com
+//      $ uservpnctl kill 17
+//
+#![allow(deprecated)]   // try!, like the daemon
+#[macro_use]
+extern crate clap;
+#[macro_use]
+extern crate serde_json;
+
+use std::io::{self,BufRead,BufReader,Write};
+use std::process;
+use serde_json::Value;
+
+#[cfg(unix)]
+fn request(path: &str, command: &Value) -> io::Result<Value> {
+    use std::os::unix::net::UnixStream;
+    let mut stream = try!(UnixStream::connect(path));
+    let mut line = command.to_string();
+    line.push('\n');
+    try!(stream.write_all(line.as_bytes()));
+    let mut answer = String::new();
+    try!(BufReader::new(stream).read_line(&mut answer));
+    serde_json::from_str(&answer).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(not(unix))]
+fn request(_path: &str, _command: &Value) -> io::Result<Value> {
+    Err(io::Error::new(io::ErrorKind::Other, "the control socket needs unix"))
+}
+
+fn text(value: &Value) -> String {
+    match *value {
+        Value::Null => "-".to_string(),
+        Value::String(ref s) => s.clone(),
+        ref v => v.to_string()
+    }
+}
+
+fn print_answer(cmd: &str, answer: &Value) {
+    match cmd {
+        "status" => {
+            for key in &["node","uptime_s","active","sessions","failed","up","down","drained"] {
+                println!("{:<10} {}", key, text(&answer[*key]));
+            }
+        },
+        "peers" => {
+            println!("{:<30} {:<24} RTT", "PEER", "ADDRESS");
+            for peer in answer["peers"].as_array().unwrap_or(&vec!()) {
+                println!("{:<30} {:<24} {}", text(&peer["peer"]), text(&peer["address"]), text(&peer["rtt_ms"]));
+            }
+        },
+        "list" => {
+            println!("{:>6} {:<22} {:<36} {:<28} {:<10} {:>10} {:>10} {:>6}",
+                     "ID", "CLIENT", "TARGET", "EXIT", "STATE", "UP", "DOWN", "AGE");
+            for s in answer["sessions"].as_array().unwrap_or(&vec!()) {
+                println!("{:>6} {:<22} {:<36} {:<28} {:<10} {:>10} {:>10} {:>5}s",
+                         text(&s["id"]), text(&s["client"]), text(&s["target"]), text(&s["exit"]),
+                         text(&s["state"]), text(&s["up"]), text(&s["down"]), text(&s["age_s"]));
+            }
+        },
+        "geoip" => {
+            for ip in answer["ips"].as_array().unwrap_or(&vec!()) {
+                println!("{:<40} {}", text(&ip["ip"]), text(&ip["country"]));
+            }
+        },
+        _ => println!("ok")
+    }
+}
+
+fn main() {
+    let matches = clap_app!(uservpnctl =>
+        (version: crate_version!())
+        (author: "Jochen Kiemes <jochen@kiemes.de>")
+        (about: "Control a running uservpn-socks5")
+        (@arg socket: -s --socket +takes_value "Control socket <path>, default /tmp/uservpn.sock")
+        (@arg json: --json                     "Print the answer as json")
+        (@subcommand status =>
+            (about: "Shows node, uptime and traffic"))
+        (@subcommand peers =>
+            (about: "Lists the peers"))
+        (@subcommand sessions =>
+            (about: "Lists the active sessions"))
+        (@subcommand geoip =>
+            (about: "Resolves a host and shows the countries of its addresses")
+            (@arg HOST: +required "Hostname or ip"))
+        (@subcommand kill =>
+            (about: "Aborts a session")
+            (@arg ID: +required "Session id"))
+        (@subcommand drain =>
+            (about: "Stops new sessions via a node")
+            (@arg NODE: +required "Node id")
+            (@arg undo: --undo "Allow new sessions again"))
+        (@subcommand reload =>
+            (about: "Reloads the config file"))
+    ).get_matches();
+
+    let path = matches.value_of("socket").unwrap_or("/tmp/uservpn.sock");
+    let number = |name: &str, value: Option<&str>| -> u64 {
+        match value.unwrap().parse::<u64>() {
+            Ok(n) => n,
+            Err(_) => {
+                eprintln!("{} must be a number", name);
+                process::exit(2)
+            }
+        }
+    };
+    let (cmd, command) = match matches.subcommand() {
+        ("status", _) => ("status", json!({ "cmd": "status" })),
+        ("peers", _) => ("peers", json!({ "cmd": "peers" })),
+        ("sessions", _) => ("list", json!({ "cmd": "list" })),
+        ("geoip", Some(m)) => ("geoip", json!({ "cmd": "geoip", "host": m.value_of("HOST").unwrap() })),
+        ("kill", Some(m)) => ("kill-session", json!({ "cmd": "kill-session", "id": number("ID", m.value_of("ID")) })),
+        ("drain", Some(m)) => ("drain-node", json!({
+            "cmd": "drain-node",
+            "node": number("NODE", m.value_of("NODE")),
+            "drain": !m.is_present("undo")
+        })),
+        ("reload", _) => ("reload-config", json!({ "cmd": "reload-config" })),
+        _ => {
+            eprintln!("{}", matches.usage());
+            process::exit(2)
+        }
+    };
+
+    let answer = match request(path, &command) {
+        Ok(answer) => answer,
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            process::exit(1)
+        }
+    };
+    if matches.is_present("json") {
+        println!("{}", answer);
+    }
+    else if answer["ok"] == true {
+        print_answer(cmd, &answer);
+    }
+    else {
+        eprintln!("{}", text(&answer["error"]));
+        for e in answer["errors"].as_array().unwrap_or(&vec!()) {
+            eprintln!("  {}", text(e));
+        }
+    }
+    if answer["ok"] != true {
+        process::exit(1)
+    }
+}
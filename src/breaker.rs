@@ -0,0 +1,229 @@
+// Circuit breakers for the proxy chains to the nodes.
+//
+// Failed connects and handshakes of client connections are counted per
+// chain. After FAILURE_THRESHOLD failures in a row the circuit opens and
+// the chain is skipped for the backoff time. Then the circuit is half open:
+// one connection may try the chain. On success the circuit closes, on
+// failure it opens again with doubled backoff up to MAX_BACKOFF_S. A
+// destination refused by the last hop is no failure, see upstream.rs.
+//
+// The states are shared with the TUI thread.
+//
+use std::cmp;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc,Mutex};
+use std::time::{Duration,Instant};
+use upstream::{ProxyChain,retain_configured};
+
+// Failures in a row, which open the circuit
+const FAILURE_THRESHOLD: u32 = 3;
+
+const MIN_BACKOFF_S: u64 = 10;
+const MAX_BACKOFF_S: u64 = 300;
+
+// A trial connection, which has not reported back by then, is given up
+const TRIAL_TIMEOUT_S: u64 = 60;
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum CircuitState {
+    Closed,
+    Open(Instant),
+    // The trial connection has been started at
+    HalfOpen(Option<Instant>)
+}
+
+impl fmt::Display for CircuitState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CircuitState::Closed => write!(f, "closed"),
+            CircuitState::Open(until) => {
+                let now = Instant::now();
+                let left = if until > now { (until - now).as_secs() } else { 0 };
+                write!(f, "open ({}s)", left)
+            },
+            CircuitState::HalfOpen(_) => write!(f, "half open")
+        }
+    }
+}
+
+struct Circuit {
+    state: CircuitState,
+    failures: u32,
+    backoff: Duration
+}
+
+impl Circuit {
+    fn new() -> Circuit {
+        Circuit {
+            state: CircuitState::Closed,
+            failures: 0,
+            backoff: Duration::from_secs(MIN_BACKOFF_S)
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Breakers {
+    circuits: Arc<Mutex<HashMap<(u8,ProxyChain),Circuit>>>
+}
+
+impl Breakers {
+    pub fn new() -> Breakers {
+        Breakers {
+            circuits: Arc::new(Mutex::new(HashMap::new()))
+        }
+    }
+
+    // Called before a connection uses the chain
+    pub fn allow(&self, id: u8, chain: &ProxyChain) -> bool {
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = match circuits.get_mut(&(id,chain.clone())) {
+            Some(circuit) => circuit,
+            None => return true
+        };
+        let now = Instant::now();
+        match circuit.state {
+            CircuitState::Closed => true,
+            CircuitState::Open(until) if now < until => false,
+            CircuitState::Open(_) => {
+                info!("Circuit of proxy {} of node {} is half open", chain, id);
+                circuit.state = CircuitState::HalfOpen(Some(now));
+                true
+            },
+            CircuitState::HalfOpen(Some(started))
+                    if now.duration_since(started) < Duration::from_secs(TRIAL_TIMEOUT_S) => false,
+            CircuitState::HalfOpen(_) => {
+                circuit.state = CircuitState::HalfOpen(Some(now));
+                true
+            }
+        }
+    }
+
+    pub fn success(&self, id: u8, chain: &ProxyChain) {
+        let mut circuits = self.circuits.lock().unwrap();
+        if let Some(circuit) = circuits.get_mut(&(id,chain.clone())) {
+            if circuit.state != CircuitState::Closed {
+                info!("Circuit of proxy {} of node {} is closed", chain, id);
+            }
+            *circuit = Circuit::new();
+        }
+    }
+
+    pub fn failure(&self, id: u8, chain: &ProxyChain) {
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = circuits.entry((id,chain.clone())).or_insert_with(Circuit::new);
+        circuit.failures += 1;
+        match circuit.state {
+            CircuitState::Closed if circuit.failures >= FAILURE_THRESHOLD => (),
+            CircuitState::HalfOpen(_) => {
+                let backoff = cmp::min(circuit.backoff.as_secs() * 2, MAX_BACKOFF_S);
+                circuit.backoff = Duration::from_secs(backoff);
+            },
+            _ => return
+        }
+        warn!("Circuit of proxy {} of node {} is open for {}s after {} failures",
+              chain, id, circuit.backoff.as_secs(), circuit.failures);
+        circuit.state = CircuitState::Open(Instant::now() + circuit.backoff);
+    }
+
+    // Circuits of chains, which are still configured, keep their state
+    pub fn retain(&self, proxy_to: &[Option<Vec<ProxyChain>>]) {
+        retain_configured(&mut *self.circuits.lock().unwrap(), proxy_to)
+    }
+
+    // Chains with failures for display
+    pub fn snapshot(&self) -> Vec<(u8,String,CircuitState,u32)> {
+        let circuits = self.circuits.lock().unwrap();
+        let mut list: Vec<(u8,String,CircuitState,u32)> = circuits.iter()
+            .map(|(&(id,ref chain),circuit)| (id, chain.to_string(), circuit.state, circuit.failures))
+            .collect();
+        list.sort_by(|a,b| (a.0,&a.1).cmp(&(b.0,&b.1)));
+        list
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chain() -> ProxyChain {
+        ProxyChain::parse("socks5://10.0.0.1:1080 > 1.2.3.4:40000").unwrap()
+    }
+
+    // Lets the backoff of an open circuit run out
+    fn expire(breakers: &Breakers, id: u8, chain: &ProxyChain) {
+        let mut circuits = breakers.circuits.lock().unwrap();
+        let circuit = circuits.get_mut(&(id,chain.clone())).unwrap();
+        circuit.state = match circuit.state {
+            CircuitState::Open(_) => CircuitState::Open(Instant::now() - Duration::from_secs(1)),
+            state => state
+        };
+    }
+
+    fn backoff(breakers: &Breakers, id: u8, chain: &ProxyChain) -> u64 {
+        breakers.circuits.lock().unwrap()[&(id,chain.clone())].backoff.as_secs()
+    }
+
+    #[test]
+    fn opens_after_threshold() {
+        let breakers = Breakers::new();
+        let chain = chain();
+        assert!(breakers.allow(1, &chain));
+        for _ in 0..FAILURE_THRESHOLD-1 {
+            breakers.failure(1, &chain);
+            assert!(breakers.allow(1, &chain));
+        }
+        breakers.failure(1, &chain);
+        assert!(!breakers.allow(1, &chain));
+        // other nodes are not affected
+        assert!(breakers.allow(2, &chain));
+    }
+
+    #[test]
+    fn success_resets_failures() {
+        let breakers = Breakers::new();
+        let chain = chain();
+        for _ in 0..FAILURE_THRESHOLD-1 {
+            breakers.failure(1, &chain);
+        }
+        breakers.success(1, &chain);
+        breakers.failure(1, &chain);
+        assert!(breakers.allow(1, &chain));
+    }
+
+    #[test]
+    fn half_open_allows_one_trial() {
+        let breakers = Breakers::new();
+        let chain = chain();
+        for _ in 0..FAILURE_THRESHOLD {
+            breakers.failure(1, &chain);
+        }
+        expire(&breakers, 1, &chain);
+        assert!(breakers.allow(1, &chain));
+        assert!(!breakers.allow(1, &chain));
+        breakers.success(1, &chain);
+        assert!(breakers.allow(1, &chain));
+        assert!(breakers.allow(1, &chain));
+    }
+
+    #[test]
+    fn failed_trial_doubles_backoff() {
+        let breakers = Breakers::new();
+        let chain = chain();
+        for _ in 0..FAILURE_THRESHOLD {
+            breakers.failure(1, &chain);
+        }
+        assert_eq!(backoff(&breakers, 1, &chain), MIN_BACKOFF_S);
+        let mut expected = MIN_BACKOFF_S;
+        for _ in 0..10 {
+            expire(&breakers, 1, &chain);
+            assert!(breakers.allow(1, &chain));
+            breakers.failure(1, &chain);
+            assert!(!breakers.allow(1, &chain));
+            expected = cmp::min(expected * 2, MAX_BACKOFF_S);
+            assert_eq!(backoff(&breakers, 1, &chain), expected);
+        }
+        assert_eq!(expected, MAX_BACKOFF_S);
+    }
+}
@@ -0,0 +1,654 @@
+// The config file is read into sections of key/value strings. Both ini and
+// yaml files are supported, a yaml file has one mapping per section:
+//
+//      Nodes:
+//        1: Frankfurt
+//      Frankfurt:
+//        Country: de
+//        PublicTCP: [1.2.3.4:40000, vpn.example.com:40001]
+//      Self:
+//        Default: 1
+//
+// A single validation pass turns the sections into the typed Config. All
+// errors are collected and reported with section, key and value. Unknown
+// keys and sections are ignored with a warning.
+//
+use std::cmp;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::Duration;
+use ini::Ini;
+use yaml_rust::{Yaml,YamlLoader};
+use address::Address;
+use database::Node;
+use dns::DnsConfig;
+use ratelimit::RateLimits;
+use region::Regions;
+use rules::{Rule,Target};
+use schedule::PriorityConfig;
+use tld::TldClassifier;
+use upstream::ProxyChain;
+
+// Weight of a country without explicit weight
+const DEFAULT_WEIGHT: u32 = 100;
+
+// Sections besides the node sections
+const SECTIONS: &[&str] = &["Nodes","Self","Regions","Rules","DNS","Tld","RateLimit","Priority"];
+
+#[derive(Debug)]
+pub struct ConfigError {
+    pub section: Option<String>,
+    pub key: Option<String>,
+    pub value: Option<String>,
+    pub message: String
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref section) = self.section {
+            write!(f, "[{}]", section)?;
+        }
+        if let Some(ref key) = self.key {
+            write!(f, " {}", key)?;
+        }
+        if let Some(ref value) = self.value {
+            write!(f, " = {}", value)?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+impl ConfigError {
+    fn file(message: String) -> ConfigError {
+        ConfigError { section: None, key: None, value: None, message }
+    }
+}
+
+pub struct Section {
+    pub name: String,
+    pub entries: Vec<(String,String)>
+}
+
+#[derive(Debug)]
+pub struct Config {
+    pub nodes: Vec<Node>,
+    // SocksProxy->N entries of all node sections
+    pub proxy_to: Vec<(u8,Vec<ProxyChain>)>,
+    pub default_node: Option<u8>,
+    pub linger: Duration,
+    pub shutdown_timeout: Duration,
+    pub remote_dns: Option<u8>,
+    pub rules: Vec<Rule>,
+    pub dns: DnsConfig,
+    pub tld: TldClassifier,
+    pub rate_limits: RateLimits,
+    pub priority: PriorityConfig
+}
+
+// Collects the errors of the validation pass
+struct Validator {
+    errors: Vec<ConfigError>,
+    regions: Regions,
+    // Node ids with the entries, which use them. Checked after all nodes
+    // have been read.
+    references: Vec<(String,String,String,u8)>
+}
+
+impl Validator {
+    fn error<M: Into<String>>(&mut self, section: &str, key: &str, value: &str, message: M) {
+        self.errors.push(ConfigError {
+            section: Some(section.to_string()),
+            key: Some(key.to_string()),
+            value: Some(value.to_string()),
+            message: message.into()
+        })
+    }
+
+    fn refer(&mut self, section: &str, key: &str, value: &str, id: u8) {
+        self.references.push((section.to_string(), key.to_string(), value.to_string(), id))
+    }
+
+    fn missing(&mut self, section: &str, message: &str) {
+        self.errors.push(ConfigError {
+            section: Some(section.to_string()),
+            key: None,
+            value: None,
+            message: message.to_string()
+        })
+    }
+
+    fn node_id(&mut self, section: &str, key: &str, value: &str, id: &str) -> Option<u8> {
+        match u8::from_str(id.trim()) {
+            Ok(id) => Some(id),
+            Err(_) => {
+                self.error(section, key, value, format!("node id <{}> is not 0..255", id));
+                None
+            }
+        }
+    }
+
+    // de:100,at:100,ch:50 or just de,at. A @region stands for all its
+    // countries. A country listed twice counts with the highest weight.
+    fn countries(&mut self, section: &str, key: &str, value: &str) -> Option<Vec<(usize,u32)>> {
+        let mut countries: Vec<(usize,u32)> = vec!();
+        for entry in value.split(",") {
+            let mut parts = entry.trim().splitn(2, ':');
+            let codes = match self.regions.expand(parts.next().unwrap()) {
+                Ok(codes) => codes,
+                Err(e) => {
+                    self.error(section, key, value, e);
+                    return None
+                }
+            };
+            let weight = match parts.next() {
+                None => DEFAULT_WEIGHT,
+                Some(w) => match u32::from_str(w.trim()) {
+                    Ok(w) if w > 0 => w,
+                    _ => {
+                        self.error(section, key, value, format!("weight in <{}> must be > 0", entry));
+                        return None
+                    }
+                }
+            };
+            for code in codes {
+                match countries.iter().position(|&(c,_)| c == code) {
+                    Some(pos) => countries[pos].1 = cmp::max(countries[pos].1, weight),
+                    None => countries.push((code,weight))
+                }
+            }
+        }
+        Some(countries)
+    }
+
+    fn addresses(&mut self, section: &str, key: &str, value: &str) -> Option<Vec<SocketAddr>> {
+        let mut sa_list: Vec<SocketAddr> = vec!();
+        for add in value.split(",") {
+            match add.trim().parse::<SocketAddr>() {
+                Ok(sa) => sa_list.push(sa),
+                Err(_) => {
+                    self.error(section, key, value, format!("<{}> is not an ip:port address", add));
+                    return None
+                }
+            }
+        }
+        if sa_list.len() > 0 { Some(sa_list) } else { None }
+    }
+
+    // Addresses of other hosts may be given as host:port
+    fn endpoints(&mut self, section: &str, key: &str, value: &str) -> Option<Vec<Address>> {
+        let mut list: Vec<Address> = vec!();
+        for add in value.split(",") {
+            match Address::parse(add) {
+                Ok(addr) => list.push(addr),
+                Err(e) => {
+                    self.error(section, key, value, e);
+                    return None
+                }
+            }
+        }
+        if list.len() > 0 { Some(list) } else { None }
+    }
+}
+
+// Chooses the format by file extension, default is ini
+pub fn load(path: &str) -> Result<Config,Vec<ConfigError>> {
+    let sections = if path.ends_with(".yaml") || path.ends_with(".yml") {
+        read_yaml(path)
+    }
+    else {
+        read_ini(path)
+    };
+    match sections {
+        Ok(sections) => validate(&sections),
+        Err(e) => Err(vec!(e))
+    }
+}
+
+pub fn read_ini(path: &str) -> Result<Vec<Section>,ConfigError> {
+    let ini = match Ini::load_from_file(path) {
+        Ok(ini) => ini,
+        Err(e) => return Err(ConfigError::file(format!("Cannot read {}: {}", path, e)))
+    };
+    let mut sections = vec!();
+    for (name, prop) in ini.iter() {
+        if let Some(ref name) = *name {
+            sections.push(Section {
+                name: name.to_string(),
+                entries: prop.iter().map(|(k,v)| (k.to_string(),v.to_string())).collect()
+            })
+        }
+    }
+    Ok(sections)
+}
+
+fn yaml_scalar(value: &Yaml) -> Option<String> {
+    match *value {
+        Yaml::String(ref s) => Some(s.clone()),
+        Yaml::Integer(i) => Some(i.to_string()),
+        Yaml::Real(ref r) => Some(r.clone()),
+        Yaml::Boolean(b) => Some(if b { "yes".to_string() } else { "no".to_string() }),
+        _ => None
+    }
+}
+
+// Lists are joined with ',' like in the ini file
+fn yaml_value(value: &Yaml) -> Option<String> {
+    match *value {
+        Yaml::Array(ref list) => {
+            let items: Option<Vec<String>> = list.iter().map(yaml_scalar).collect();
+            items.map(|items| items.join(","))
+        },
+        _ => yaml_scalar(value)
+    }
+}
+
+pub fn read_yaml(path: &str) -> Result<Vec<Section>,ConfigError> {
+    let mut content = String::new();
+    if let Err(e) = File::open(path).and_then(|mut f| f.read_to_string(&mut content)) {
+        return Err(ConfigError::file(format!("Cannot read {}: {}", path, e)))
+    }
+    let docs = match YamlLoader::load_from_str(&content) {
+        Ok(docs) => docs,
+        Err(e) => return Err(ConfigError::file(format!("{}: {}", path, e)))
+    };
+    let top = match docs.into_iter().next() {
+        Some(Yaml::Hash(top)) => top,
+        _ => return Err(ConfigError::file(format!("{}: expected a mapping of sections", path)))
+    };
+    let mut sections = vec!();
+    for (name, body) in top {
+        let name = match yaml_scalar(&name) {
+            Some(name) => name,
+            None => return Err(ConfigError::file(format!("{}: bad section name {:?}", path, name)))
+        };
+        let mut entries = vec!();
+        if let Yaml::Hash(body) = body {
+            for (k,v) in body {
+                match (yaml_scalar(&k), yaml_value(&v)) {
+                    (Some(k), Some(v)) => entries.push((k,v)),
+                    (k, _) => return Err(ConfigError {
+                        section: Some(name),
+                        key: k,
+                        value: None,
+                        message: "expected a value or a list of values".to_string()
+                    })
+                }
+            }
+        }
+        else {
+            return Err(ConfigError {
+                section: Some(name),
+                key: None,
+                value: None,
+                message: "expected a mapping".to_string()
+            })
+        }
+        sections.push(Section { name, entries })
+    }
+    Ok(sections)
+}
+
+fn find<'a>(sections: &'a [Section], name: &str) -> Option<&'a Section> {
+    sections.iter().find(|s| s.name == name)
+}
+
+fn read_node(v: &mut Validator, id: u8, section: &Section,
+             proxy_to: &mut Vec<(u8,Vec<ProxyChain>)>) -> Node {
+    let name = &section.name;
+    let mut node = Node {
+        id,
+        name: name.to_string(),
+        probe: None,
+        countries: vec!(),
+        socks5_listen_port: None,
+        socks_server_ports: None,
+        public_tcp: None,
+        public_udp: None,
+        bind_tcp : None,
+        dns: None
+    };
+    for &(ref k, ref val) in &section.entries {
+        match k.as_ref() {
+            "Probe" => {
+                match Address::parse(val) {
+                    Ok(target) => node.probe = Some(target),
+                    Err(e) => v.error(name, k, val, e)
+                }
+            },
+            "Socks5Address" => {
+                match val.trim().parse::<SocketAddr>() {
+                    Ok(sa) => node.socks5_listen_port = Some(sa),
+                    Err(_) => v.error(name, k, val, "is not an ip:port address")
+                }
+            },
+            "PublicTCP" => node.public_tcp = v.endpoints(name, k, val),
+            "BindTCP" => node.bind_tcp = v.addresses(name, k, val),
+            "PublicUDP" => node.public_udp = v.endpoints(name, k, val),
+            "SocksServerPorts" => node.socks_server_ports = v.addresses(name, k, val),
+            "DNS" => {
+                let mut dns = DnsConfig::default();
+                match dns.read_servers(val) {
+                    Ok(()) => node.dns = Some(dns),
+                    Err(e) => v.error(name, k, val, e)
+                }
+            },
+            "Country" => {
+                if let Some(countries) = v.countries(name, k, val) {
+                    node.countries = countries
+                }
+            },
+            _ if k.starts_with("SocksProxy->") => {
+                let to_id = v.node_id(name, k, val, &k[12..]);
+                let chains = val.split(",")
+                                .map(ProxyChain::parse)
+                                .collect::<Result<Vec<ProxyChain>,String>>();
+                match (to_id, chains) {
+                    (Some(to_id), Ok(chains)) => {
+                        v.refer(name, k, val, to_id);
+                        proxy_to.push((to_id, chains))
+                    },
+                    (_, Err(e)) => v.error(name, k, val, e),
+                    (None, _) => ()
+                }
+            },
+            _ => warn!("Ignore unknown key [{}] {} = {}", name, k, val)
+        }
+    }
+    node
+}
+
+pub fn validate(sections: &[Section]) -> Result<Config,Vec<ConfigError>> {
+    let mut v = Validator { errors: vec!(), regions: Regions::new(), references: vec!() };
+    let mut config = Config {
+        nodes: vec!(),
+        proxy_to: vec!(),
+        default_node: None,
+        linger: Duration::from_secs(60),
+        shutdown_timeout: Duration::from_secs(30),
+        remote_dns: None,
+        rules: vec!(),
+        dns: DnsConfig::default(),
+        tld: TldClassifier::new(),
+        rate_limits: RateLimits::default(),
+        priority: PriorityConfig::default()
+    };
+
+    // Regions are needed by the node sections and the rules
+    if let Some(section) = find(sections, "Regions") {
+        for &(ref k, ref val) in &section.entries {
+            if let Err(e) = v.regions.define(k, val) {
+                v.error("Regions", k, val, e)
+            }
+        }
+        for &(ref k, ref val) in &section.entries {
+            if let Err(e) = v.regions.expand(&format!("@{}", k.trim().trim_start_matches('@'))) {
+                v.error("Regions", k, val, e)
+            }
+        }
+    }
+
+    // Sections, which are node sections
+    let mut node_sections: HashMap<String,u8> = HashMap::new();
+    match find(sections, "Nodes") {
+        Some(section) => {
+            for &(ref k, ref val) in &section.entries {
+                let id = match v.node_id("Nodes", k, val, k) {
+                    Some(id) => id,
+                    None => continue
+                };
+                match find(sections, val) {
+                    Some(node_section) => {
+                        node_sections.insert(val.to_string(), id);
+                        let node = read_node(&mut v, id, node_section, &mut config.proxy_to);
+                        config.nodes.push(node)
+                    },
+                    None => v.error("Nodes", k, val, format!("section [{}] is missing", val))
+                }
+            }
+        },
+        None => v.missing("Nodes", "section is missing")
+    }
+
+    match find(sections, "Self") {
+        Some(section) => {
+            for &(ref k, ref val) in &section.entries {
+                match k.as_ref() {
+                    "Default" => {
+                        config.default_node = v.node_id("Self", k, val, val);
+                        if let Some(id) = config.default_node {
+                            v.refer("Self", k, val, id)
+                        }
+                    },
+                    "LingerTimeout" => {
+                        match u64::from_str(val.trim()) {
+                            Ok(secs) => config.linger = Duration::from_secs(secs),
+                            Err(_) => v.error("Self", k, val, "must be seconds")
+                        }
+                    },
+                    "ShutdownTimeout" => {
+                        match u64::from_str(val.trim()) {
+                            Ok(secs) => config.shutdown_timeout = Duration::from_secs(secs),
+                            Err(_) => v.error("Self", k, val, "must be seconds")
+                        }
+                    },
+                    "RemoteDNS" => {
+                        config.remote_dns = v.node_id("Self", k, val, val);
+                        if let Some(id) = config.remote_dns {
+                            v.refer("Self", k, val, id)
+                        }
+                    },
+                    _ => warn!("Ignore unknown key [Self] {} = {}", k, val)
+                }
+            }
+        },
+        None => v.missing("Self", "section is missing")
+    }
+
+    if let Some(section) = find(sections, "Rules") {
+        let mut numbered: Vec<(u32,Rule)> = vec!();
+        for &(ref k, ref val) in &section.entries {
+            let nr = match u32::from_str(k) {
+                Ok(nr) => nr,
+                Err(_) => {
+                    v.error("Rules", k, val, "rule key must be a number");
+                    continue
+                }
+            };
+            match Rule::parse(val, &v.regions) {
+                Ok(rule) => {
+                    if let Target::Node(id) = rule.target {
+                        v.refer("Rules", k, val, id)
+                    }
+                    numbered.push((nr,rule))
+                },
+                Err(e) => v.error("Rules", k, val, e)
+            }
+        }
+        numbered.sort_by_key(|&(nr,_)| nr);
+        config.rules = numbered.into_iter().map(|(_,rule)| rule).collect();
+    }
+
+    if let Some(section) = find(sections, "DNS") {
+        for &(ref k, ref val) in &section.entries {
+            let res = match k.as_ref() {
+                "Servers" => config.dns.read_servers(val),
+                "System" => config.dns.read_system(val),
+                _ => {
+                    warn!("Ignore unknown key [DNS] {} = {}", k, val);
+                    continue
+                }
+            };
+            if let Err(e) = res {
+                v.error("DNS", k, val, e)
+            }
+        }
+    }
+
+    if let Some(section) = find(sections, "Tld") {
+        for &(ref k, ref val) in &section.entries {
+            if let Err(e) = config.tld.read_entry(k,val) {
+                v.error("Tld", k, val, e)
+            }
+        }
+    }
+
+    if let Some(section) = find(sections, "RateLimit") {
+        for &(ref k, ref val) in &section.entries {
+            if k != "Global" && !k.starts_with("Node->") && !k.starts_with("Client->") {
+                warn!("Ignore unknown key [RateLimit] {} = {}", k, val);
+                continue
+            }
+            if let Err(e) = config.rate_limits.read_entry(k,val) {
+                v.error("RateLimit", k, val, e);
+                continue
+            }
+            if k.starts_with("Node->") {
+                if let Ok(id) = u8::from_str(&k[6..]) {
+                    v.refer("RateLimit", k, val, id)
+                }
+            }
+        }
+    }
+
+    if let Some(section) = find(sections, "Priority") {
+        for &(ref k, ref val) in &section.entries {
+            if k != "Interactive" {
+                warn!("Ignore unknown key [Priority] {} = {}", k, val);
+                continue
+            }
+            if let Err(e) = config.priority.read_entry(k,val) {
+                v.error("Priority", k, val, e)
+            }
+        }
+    }
+
+    for section in sections {
+        if !SECTIONS.contains(&section.name.as_str()) && !node_sections.contains_key(&section.name) {
+            warn!("Ignore unknown section [{}]", section.name);
+        }
+    }
+
+    check_references(&mut v, &config);
+
+    if v.errors.len() > 0 {
+        return Err(v.errors)
+    }
+    Ok(config)
+}
+
+// All node ids used in the config have to be listed in [Nodes]
+fn check_references(v: &mut Validator, config: &Config) {
+    let references = ::std::mem::take(&mut v.references);
+    for (section, key, value, id) in references {
+        if !config.nodes.iter().any(|node| node.id == id) {
+            v.error(&section, &key, &value, "node is not listed in [Nodes]");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use country::country_hash;
+
+    fn sections(list: &[(&str,&[(&str,&str)])]) -> Vec<Section> {
+        list.iter().map(|&(name,entries)| Section {
+            name: name.to_string(),
+            entries: entries.iter().map(|&(k,v)| (k.to_string(), v.to_string())).collect()
+        }).collect()
+    }
+
+    fn keys(errors: &[ConfigError]) -> Vec<(String,String)> {
+        errors.iter()
+              .map(|e| (e.section.clone().unwrap_or_default(), e.key.clone().unwrap_or_default()))
+              .collect()
+    }
+
+    #[test]
+    fn valid_config() {
+        let config = validate(&sections(&[
+            ("Nodes", &[("1","Frankfurt"), ("2","Paris")]),
+            ("Frankfurt", &[("Country","de:100,at:50"),
+                            ("PublicUDP","1.2.3.4:40000"),
+                            ("SocksProxy->2","socks5://10.0.0.1:1080 > 5.6.7.8:40000, 5.6.7.9:40000")]),
+            ("Paris", &[("Country","@dach:20,fr"), ("Probe","example.org:80")]),
+            ("Regions", &[("dach","de,at,ch")]),
+            ("Self", &[("Default","1"), ("LingerTimeout","10"), ("RemoteDNS","2")]),
+            ("Rules", &[("20","*.example.org -> node 2"), ("10","10.0.0.0/8 -> direct")])
+        ])).unwrap();
+        let de = country_hash(b"de").unwrap();
+        let at = country_hash(b"at").unwrap();
+        let ch = country_hash(b"ch").unwrap();
+        let fr = country_hash(b"fr").unwrap();
+        assert_eq!(config.nodes.len(), 2);
+        assert_eq!(config.nodes[0].countries, vec!((de,100),(at,50)));
+        assert_eq!(config.nodes[1].countries, vec!((de,20),(at,20),(ch,20),(fr,100)));
+        assert_eq!(config.proxy_to.len(), 1);
+        assert_eq!(config.proxy_to[0].0, 2);
+        assert_eq!(config.proxy_to[0].1.len(), 2);
+        assert_eq!(config.default_node, Some(1));
+        assert_eq!(config.remote_dns, Some(2));
+        assert_eq!(config.linger, Duration::from_secs(10));
+        assert_eq!(config.shutdown_timeout, Duration::from_secs(30));
+        // Rules are ordered by their keys
+        assert_eq!(config.rules[0].target, Target::Direct);
+        assert_eq!(config.rules[1].target, Target::Node(2));
+    }
+
+    #[test]
+    fn all_errors_are_collected() {
+        let errors = validate(&sections(&[
+            ("Nodes", &[("1","Frankfurt"), ("x","Paris"), ("3","Rome")]),
+            ("Frankfurt", &[("Country","de:0"),
+                            ("PublicUDP","1.2.3.4"),
+                            ("SocksProxy->9","ssh://10.0.0.1:22"),
+                            ("SocksProxy->8","1.2.3.4:40000")]),
+            ("Self", &[("Default","7"), ("LingerTimeout","soon")]),
+            ("Rules", &[("a","*.example.org -> node 1"), ("10","*.example.com -> node 5")]),
+            ("RateLimit", &[("Node->4","100")])
+        ])).unwrap_err();
+        assert_eq!(keys(&errors), vec!(
+            ("Frankfurt".to_string(), "Country".to_string()),
+            ("Frankfurt".to_string(), "PublicUDP".to_string()),
+            ("Frankfurt".to_string(), "SocksProxy->9".to_string()),
+            ("Nodes".to_string(), "x".to_string()),
+            ("Nodes".to_string(), "3".to_string()),
+            ("Self".to_string(), "LingerTimeout".to_string()),
+            ("Rules".to_string(), "a".to_string()),
+            ("Frankfurt".to_string(), "SocksProxy->8".to_string()),
+            ("Self".to_string(), "Default".to_string()),
+            ("Rules".to_string(), "10".to_string()),
+            ("RateLimit".to_string(), "Node->4".to_string())
+        ));
+        let rule = errors.iter().find(|e| e.section == Some("Rules".to_string())
+                                          && e.key == Some("10".to_string())).unwrap();
+        assert_eq!(rule.to_string(), "[Rules] 10 = *.example.com -> node 5: node is not listed in [Nodes]");
+    }
+
+    #[test]
+    fn missing_sections() {
+        let errors = validate(&[]).unwrap_err();
+        assert_eq!(keys(&errors), vec!(
+            ("Nodes".to_string(), String::new()),
+            ("Self".to_string(), String::new())
+        ));
+    }
+
+    #[test]
+    fn bad_regions() {
+        let errors = validate(&sections(&[
+            ("Nodes", &[]),
+            ("Self", &[]),
+            ("Regions", &[("a","@b"), ("b","@a"), ("c","de,@atlantis")])
+        ])).unwrap_err();
+        assert_eq!(keys(&errors), vec!(
+            ("Regions".to_string(), "a".to_string()),
+            ("Regions".to_string(), "b".to_string()),
+            ("Regions".to_string(), "c".to_string())
+        ));
+    }
+}
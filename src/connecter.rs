@@ -2,20 +2,82 @@
 use std::io::{self,Write};
 use std::net::{SocketAddr,IpAddr, Ipv4Addr, Ipv6Addr};
 use std::rc::Rc;
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::{HashMap,HashSet};
 use std::option::Option;
-use std::time::Instant;
+use std::time::{Instant,Duration};
 
-use futures::{Future, Async, Join};
+use futures::{future, Future, Async, Sink};
+use futures::sync::mpsc::Sender;
+use futures::sync::oneshot;
 use tokio_core::net::{TcpStream,TcpStreamNew};
-use tokio_core::reactor::Handle;
+use tokio_core::reactor::{Handle,Timeout};
 use trust_dns_resolver::config::*;
 use trust_dns_resolver;
 use trust_dns_resolver::lookup_ip::LookupIpFuture;
 use socksv5_future::*;
 use csv;
+use rand::{self,Rng};
+use address::{Address,AddressBook};
+use health::{self,Health};
+use breaker::Breakers;
+use sessions::{Sessions,SessionHandle,SessionState};
+use config::{self,ConfigError};
 use database::Database;
 use country::{code2country,country_hash};
-use transfer::Transfer;
+use rules::{self,Route,Target};
+use message::PeerMessage;
+use dnscache::{DnsCache,CacheLookup,Resolved};
+use accounting::{Accounting,SessionRecord,CloseReason,Exit,millis};
+use transfer::{Relay,Traffic};
+use upstream::{Handshake,ProxyChain,is_destination_error,socks_success_reply};
+use ratelimit::{Limiter,RateLimiters};
+use schedule::{Priority,Scheduler};
+
+// Time to wait for the answer of a remote dns query
+const REMOTE_DNS_TIMEOUT_MS: u64 = 5_000;
+
+// Neither remote dns answers nor the lookups of the resolver tell the TTL,
+// so resolved hostnames are cached for this time
+const DNS_TTL_S: u64 = 60;
+
+// Addresses and country codes of a hostname as resolved by a peer
+pub type RemoteAnswer = (Vec<IpAddr>,Vec<usize>);
+
+// host:port or ip:port of the socks request for display
+fn request_target(request: &Option<SocksRequestResponse>) -> String {
+    match *request {
+        Some(ref req) => {
+            match (req.ipaddr(),req.hostname()) {
+                (Some(ip),_) => SocketAddr::new(ip,req.port()).to_string(),
+                (None,Some(host)) => format!("{}:{}",String::from_utf8_lossy(host),req.port()),
+                (None,None) => "-".to_string()
+            }
+        },
+        None => "-".to_string()
+    }
+}
+
+// Weighted random order without repetition. Nodes with weight 0 follow
+// the others in their given order.
+fn weighted_order(mut candidates: Vec<(u8,u32)>) -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    let mut order: Vec<u8> = vec!();
+    loop {
+        let total: u64 = candidates.iter().map(|&(_,w)| w as u64).sum();
+        if total == 0 {
+            break
+        }
+        let mut pick = rng.gen_range(0, total);
+        let pos = candidates.iter().position(|&(_,w)| {
+            if pick < w as u64 { true } else { pick -= w as u64; false }
+        }).unwrap();
+        order.push(candidates.remove(pos).0);
+    }
+    order.extend(candidates.into_iter().map(|(id,_)| id));
+    order
+}
 
 enum RFState {
     Resolve(LookupIpFuture),
@@ -23,7 +85,7 @@ enum RFState {
     Connecting(TcpStreamNew),
     SendOK,
     InitiateTransfer,
-    WaitTransfer(Join<Transfer,Transfer>)
+    WaitTransfer(Relay)
 }
 
 pub struct ResolverFuture {
@@ -32,7 +94,43 @@ pub struct ResolverFuture {
     srr: Option<SocksRequestResponse>,
     source: Option<TcpStream>,
     destination: Option<TcpStream>,
-    ips: Vec<IpAddr>
+    ips: Vec<IpAddr>,
+    accounting: Accounting,
+    client: Option<SocketAddr>,
+    opened: Instant,
+    connect_ms: Option<u64>,
+    traffic: Traffic,
+    linger: Duration,
+    limiter: Option<Limiter>,
+    priority: Option<(Rc<Scheduler>,Priority)>,
+    session: SessionHandle,
+    recorded: bool
+}
+
+impl ResolverFuture {
+    fn finish(&mut self, reason: CloseReason) {
+        if self.recorded {
+            return
+        }
+        self.recorded = true;
+        self.accounting.record(SessionRecord {
+            client: self.client,
+            target: request_target(&self.srr),
+            exit: Exit::Direct,
+            country: None,
+            bytes_up: self.traffic.up(),
+            bytes_down: self.traffic.down(),
+            connect_ms: self.connect_ms,
+            duration: self.opened.elapsed(),
+            reason
+        })
+    }
+}
+
+impl Drop for ResolverFuture {
+    fn drop(&mut self) {
+        self.finish(CloseReason::Aborted)
+    }
 }
 
 impl Future for ResolverFuture
@@ -41,6 +139,21 @@ impl Future for ResolverFuture
     type Error = io::Error;
 
     fn poll(&mut self) -> Result<Async<Self::Item>, Self::Error> {
+        let res = match self.session.poll_killed() {
+            Err(e) => Err(e),
+            Ok(_) => self.poll_state()
+        };
+        match res {
+            Ok(Async::Ready(())) => self.finish(CloseReason::Closed),
+            Err(ref e) => self.finish(CloseReason::Failed(e.to_string())),
+            Ok(Async::NotReady) => ()
+        }
+        res
+    }
+}
+
+impl ResolverFuture {
+    fn poll_state(&mut self) -> Result<Async<()>, io::Error> {
         trace!("Poll");
         loop {
             self.state = match self.state {
@@ -56,6 +169,7 @@ impl Future for ResolverFuture
                     if let Some(ip) = self.ips.pop() {
                         debug!("{:?}",ip);
                         let sa = SocketAddr::new(ip,self.srr.as_ref().unwrap().port());
+                        self.session.set_state(SessionState::Connecting);
                         RFState::Connecting(TcpStream::connect(&sa,&self.handle))
                     }
                     else {
@@ -86,47 +200,235 @@ impl Future for ResolverFuture
                     response.bytes[1] = 0;
                     let m = try!(source.write(&response.bytes.to_vec()));
                     assert_eq!(response.bytes.len(), m);
+                    self.connect_ms = Some(millis(self.opened.elapsed()));
+                    self.session.set_exit(Exit::Direct);
+                    self.session.set_state(SessionState::Relaying);
                     RFState::InitiateTransfer
                 },
                 RFState::InitiateTransfer => {
-                    let mut source = self.source.take().unwrap();
-                    let mut destination = self.destination.take().unwrap();
-                    let c1 = Rc::new(source);
-                    let c2 = Rc::new(destination);
-
-                    let half1 = Transfer::new(c1.clone(), c2.clone());
-                    let half2 = Transfer::new(c2, c1);
-                    RFState::WaitTransfer(half1.join(half2))
+                    let source = self.source.take().unwrap();
+                    let destination = self.destination.take().unwrap();
+                    RFState::WaitTransfer(Relay::new(source, destination, &self.handle,
+                                                     &self.traffic, self.linger,
+                                                     self.limiter.take(), self.priority.take()))
                 }
                 RFState::WaitTransfer(ref mut fut) => {
-                    let transferred = try_ready!(fut.poll());
+                    // Bytes are counted in traffic
+                    try_ready!(fut.poll());
                     return Ok(Async::Ready(()));
                 }
             };
         }
-        error!("Should Not come here");
-        Ok(Async::NotReady)
     }
 }
 
+// The database, resolver and rate limits are replaced on reload of the
+// config. Running connections keep the database they have started with.
 pub struct Connecter {
     dbip_v4: Vec<(Ipv4Addr,Ipv4Addr,usize)>,
-    resolver: trust_dns_resolver::ResolverFuture,
+    resolver: RefCell<trust_dns_resolver::ResolverFuture>,
     handle: Handle,
-    database: Rc<Database>
+    node_id: u8,
+    database: RefCell<Rc<Database>>,
+    peer_tx: Option<Sender<(SocketAddr, Vec<u8>)>>,
+    // Queried peer and waiting connection per query id
+    pending_queries: RefCell<HashMap<u32,(SocketAddr,oneshot::Sender<RemoteAnswer>)>>,
+    cache: DnsCache,
+    accounting: Accounting,
+    limiters: RefCell<RateLimiters>,
+    scheduler: Rc<Scheduler>,
+    addresses: AddressBook,
+    peers: Vec<Address>,
+    health: Health,
+    breakers: Breakers,
+    sessions: Sessions,
+    // Nodes, which get no new sessions
+    drained: RefCell<HashSet<u8>>
+}
+
+fn build_resolver(handle: &Handle, database: &Database, node_id: u8) -> trust_dns_resolver::ResolverFuture {
+    let dns = database.dns_for(node_id);
+    let (config,opts) = match dns.resolver_config() {
+        Ok(conf) => conf,
+        Err(e) => {
+            error!("Cannot configure resolver: {}. Use default",e);
+            (ResolverConfig::default(),ResolverOpts::default())
+        }
+    };
+    if dns.is_configured() {
+        info!("Name servers: {:?}",config.name_servers());
+    }
+    trust_dns_resolver::ResolverFuture::new(config, opts, handle)
 }
 
 impl Connecter {
-    pub fn new(handle: Handle,database: Rc<Database>) -> Connecter {
-        let resolver = trust_dns_resolver::ResolverFuture::new(ResolverConfig::default(),
-                                        ResolverOpts::default(), 
-                                        &handle);
+    pub fn new(handle: Handle,database: Rc<Database>,node_id: u8) -> Connecter {
+        let resolver = build_resolver(&handle, &database, node_id);
+        let limiters = RateLimiters::new(&database.rate_limits);
         Connecter {
             dbip_v4: vec!(),
-            resolver,
+            resolver: RefCell::new(resolver),
             handle,
-            database
+            node_id,
+            database: RefCell::new(database),
+            peer_tx: None,
+            pending_queries: RefCell::new(HashMap::new()),
+            cache: DnsCache::new(),
+            accounting: Accounting::new(),
+            limiters: RefCell::new(limiters),
+            scheduler: Rc::new(Scheduler::default()),
+            addresses: AddressBook::new(),
+            peers: vec!(),
+            health: Health::new(),
+            breakers: Breakers::new(),
+            sessions: Sessions::new(),
+            drained: RefCell::new(HashSet::new())
+        }
+    }
+
+    pub fn database(&self) -> Rc<Database> {
+        self.database.borrow().clone()
+    }
+
+    // New connections use the new database. The cached exits may refer
+    // to proxies, which are gone, so the dns cache is cleared as well.
+    pub fn swap_database(&self, database: Rc<Database>) {
+        *self.resolver.borrow_mut() = build_resolver(&self.handle, &database, self.node_id);
+        *self.limiters.borrow_mut() = RateLimiters::new(&database.rate_limits);
+        self.health.retain(&database.proxy_to);
+        self.breakers.retain(&database.proxy_to);
+        *self.database.borrow_mut() = database;
+        self.cache.clear();
+    }
+
+    pub fn accounting(&self) -> Accounting {
+        self.accounting.clone()
+    }
+
+    pub fn breakers(&self) -> Breakers {
+        self.breakers.clone()
+    }
+
+    pub fn node_id(&self) -> u8 {
+        self.node_id
+    }
+
+    pub fn drained(&self) -> Vec<u8> {
+        let mut drained: Vec<u8> = self.drained.borrow().iter().cloned().collect();
+        drained.sort();
+        drained
+    }
+
+    pub fn sessions(&self) -> Sessions {
+        self.sessions.clone()
+    }
+
+    // Running sessions via a drained node continue, new ones use other nodes
+    pub fn drain_node(&self, id: u8, drain: bool) {
+        if drain {
+            info!("Drain node {}",id);
+            self.drained.borrow_mut().insert(id);
+        }
+        else {
+            info!("Node {} is no longer drained",id);
+            self.drained.borrow_mut().remove(&id);
+        }
+    }
+
+    // Used by SIGHUP and the control socket. On error the old config stays.
+    pub fn reload_config(self: &Connecter, conn: Rc<Connecter>, path: &str) -> Result<(),Vec<ConfigError>> {
+        let config = try!(config::load(path));
+        self.swap_database(Database::from_config(config));
+        // New hostnames should not wait for the next refresh
+        self.handle.spawn(self.refresh_addresses(conn));
+        info!("Reloaded {}",path);
+        Ok(())
+    }
+
+    // Buckets for a connection from client via exit node
+    pub fn limiter(&self, client: Option<SocketAddr>, node: Option<u8>) -> Option<Limiter> {
+        self.limiters.borrow().limiter(client.map(|sa| sa.ip()), node)
+    }
+
+    // Messages to peers are only limited by the global rate
+    pub fn peer_limiter(&self) -> Option<Limiter> {
+        self.limiters.borrow().limiter(None, None)
+    }
+
+    // Dns queries, answers and close are sent via the interactive queue
+    // of the udp sender
+    pub fn set_peer_sender(&mut self, tx: Sender<(SocketAddr, Vec<u8>)>) {
+        self.peer_tx = Some(tx)
+    }
+
+    // Tells all peers, that this node is shutting down
+    pub fn notify_close(&self) {
+        let mut tx = match self.peer_tx {
+            Some(ref tx) => tx.clone(),
+            None => return
+        };
+        let close = PeerMessage::Close { node: self.node_id }.encode().unwrap();
+        for peer in &self.peers {
+            match self.addresses.lookup(peer) {
+                Some(ad) => {
+                    if let Err(e) = tx.try_send((ad, close.clone())) {
+                        warn!("Cannot send close to {}: {:?}",peer,e);
+                    }
+                },
+                None => debug!("Peer {} is not resolved, no close sent",peer)
+            }
+        }
+    }
+
+    // Peers given on the command line
+    pub fn set_peers(&mut self, peers: Vec<Address>) {
+        self.peers = peers
+    }
+
+    pub fn peers(&self) -> &[Address] {
+        &self.peers
+    }
+
+    pub fn address_book(&self) -> &AddressBook {
+        &self.addresses
+    }
+
+    // Hostnames of peers, public addresses and proxies in the current database
+    fn hostnames(&self) -> Vec<String> {
+        let db = self.database();
+        let mut addresses: Vec<&Address> = self.peers.iter().collect();
+        for node in db.nodes.iter().filter_map(|node| node.as_ref()) {
+            addresses.extend(node.public_tcp.iter().flat_map(|list| list.iter()));
+            addresses.extend(node.public_udp.iter().flat_map(|list| list.iter()));
+        }
+        for chains in db.proxy_to.iter().filter_map(|chains| chains.as_ref()) {
+            // Only the first hop is connected to directly
+            addresses.extend(chains.iter().map(|chain| &chain.hops[0].addr));
         }
+        let mut hosts: Vec<String> = addresses.iter()
+                                              .filter_map(|addr| addr.host())
+                                              .map(|host| host.to_string())
+                                              .collect();
+        hosts.sort();
+        hosts.dedup();
+        hosts
+    }
+
+    // Resolves all hostnames again. Changed addresses are used by new
+    // connections, a failed lookup keeps the previous addresses.
+    pub fn refresh_addresses(self: &Connecter, conn: Rc<Connecter>) -> Box<Future<Item=(),Error=()>> {
+        let lookups: Vec<Box<Future<Item=(),Error=()>>> = self.hostnames().into_iter().map(|host| {
+            let conn = conn.clone();
+            let lookup = self.resolver.borrow().lookup_ip(&format!("{}.",host));
+            Box::new(lookup.then(move |res| {
+                match res {
+                    Ok(lookup_ip) => conn.addresses.update(&host, lookup_ip.iter().collect()),
+                    Err(e) => warn!("Cannot resolve {}: {:?}",host,e)
+                }
+                Ok(())
+            })) as Box<Future<Item=(),Error=()>>
+        }).collect();
+        Box::new(future::join_all(lookups).map(|_| ()))
     }
 
     pub fn read_dbip(&mut self) {
@@ -149,7 +451,7 @@ impl Connecter {
                         };
                         let ipv6_from = ip_from.parse::<Ipv6Addr>();
                         let ipv6_to   = ip_to.parse::<Ipv6Addr>();
-                        if let (Ok(ipv6_from),Ok(ipv6_to),Some(code)) = (ipv6_from,ipv6_to,code) {
+                        if let (Ok(_),Ok(_),Some(_)) = (ipv6_from,ipv6_to,code) {
                             continue
                         }
                         else {
@@ -163,8 +465,8 @@ impl Connecter {
     }
 
     fn determine_country(&self,ip: &IpAddr) -> Option<usize> {
-        match ip {
-            &IpAddr::V4(ref ipv4) => {
+        match *ip {
+            IpAddr::V4(ref ipv4) => {
                 let mut i: usize = 0;
                 let mut j: usize = self.dbip_v4.len()-1;
                 while i < j {
@@ -187,33 +489,281 @@ impl Connecter {
                 }
                 None
             },
-            &IpAddr::V6(ref ipv6) => None
+            IpAddr::V6(_) => None
         }
     }
 
-    fn select_proxy(self: &Connecter, codes: &Vec<usize>) -> Vec<SocketAddr> {
-        let mut id_list: Vec<u8> = vec!();
+    // Addresses of a host with their country for display
+    pub fn geoip(self: &Connecter, conn: Rc<Connecter>, host: &str)
+                -> Box<Future<Item=Vec<(IpAddr,Option<usize>)>,Error=io::Error>> {
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Box::new(future::ok(vec!((ip,self.determine_country(&ip)))))
+        }
+        Box::new(self.resolver.borrow().lookup_ip(&format!("{}.",host))
+            .map_err(io::Error::from)
+            .map(move |lookup_ip| lookup_ip.iter().map(|ip| (ip,conn.determine_country(&ip))).collect()))
+    }
+
+    fn countries_of(self: &Connecter, ips: &Vec<IpAddr>) -> Vec<usize> {
+        let mut codes: Vec<usize> = vec!();
+        for ip in ips {
+            let code = self.determine_country(ip);
+            match code {
+                Some(code) => {
+                    if !(codes.contains(&code)) {
+                        codes.push(code);
+                    };
+                    debug!("IP {:?} -> {:?}",ip,code2country(code))
+                },
+                None => 
+                    debug!("IP {:?} -> unknown country",ip)
+            }
+        };
+        codes
+    }
+
+    // Nodes of all countries are ordered by weighted random choice. A node
+    // listed for several countries counts with its highest weight.
+    fn select_proxy(self: &Connecter, db: &Database, codes: &Vec<usize>) -> Vec<(u8,ProxyChain)> {
+        let mut candidates: Vec<(u8,u32)> = vec!();
         for cx in codes {
-            if let Some(ref xid_list) = self.database.country_to_nodes[*cx as usize] {
-                for id in xid_list {
-                    if ! id_list.contains(id) {
-                        id_list.push(*id)
+            if let Some(ref xid_list) = db.country_to_nodes[*cx] {
+                for &(id,weight) in xid_list {
+                    match candidates.iter().position(|&(cid,_)| cid == id) {
+                        Some(pos) => candidates[pos].1 = cmp::max(candidates[pos].1,weight),
+                        None => candidates.push((id,weight))
                     }
                 }
             }
         }
-        let mut sa_list: Vec<SocketAddr> = vec!();
-        for id in id_list {
-            if let Some(ref proxies) = self.database.proxy_to[id as usize] {
-                for sa in proxies {
-                    sa_list.push(sa.clone())
+        // Proxies are taken from the end of the list, so the first choice goes last
+        let mut sa_list: Vec<(u8,ProxyChain)> = vec!();
+        for id in weighted_order(candidates).into_iter().rev() {
+            sa_list.extend(self.proxies_of_node(db, id));
+        }
+        sa_list
+    }
+
+    fn proxies_of_node(self: &Connecter, db: &Database, id: u8) -> Vec<(u8,ProxyChain)> {
+        if self.drained.borrow().contains(&id) {
+            return vec!()
+        }
+        match db.proxy_to[id as usize] {
+            Some(ref proxies) => proxies.iter()
+                                        .filter(|chain| self.health.is_up(id, chain))
+                                        .map(|chain| (id,chain.clone()))
+                                        .collect(),
+            None => vec!()
+        }
+    }
+
+    // Rules are checked in one pass, first match wins
+    fn route<'a>(self: &Connecter, db: &'a Database, host: Option<&str>, ips: Option<&[IpAddr]>,
+                 codes: Option<&[usize]>) -> Route<'a> {
+        let route = rules::route(&db.rules, host, ips, codes);
+        if let Route::Matched(rule) = route {
+            debug!("{:?} {:?} {:?} matches rule -> {}",host,ips,codes,rule.target);
+        }
+        route
+    }
+
+    // Priority class of a stream, if scheduling is configured. The priority
+    // of the rule, which routed the stream, takes precedence over the port.
+    fn priority_of(self: &Connecter, db: &Database, by_rule: Option<Priority>, host: Option<&str>,
+                   port: u16) -> Option<(Rc<Scheduler>,Priority)> {
+        if db.priority.interactive_ports.is_empty()
+                && db.rules.iter().all(|rule| rule.priority.is_none()) {
+            return None
+        }
+        let priority = match by_rule {
+            Some(priority) => priority,
+            None if db.priority.interactive_ports.contains(&port) => Priority::Interactive,
+            None => Priority::Bulk(1)
+        };
+        debug!("Priority {} for {}:{}",priority,host.unwrap_or("-"),port);
+        Some((self.scheduler.clone(),priority))
+    }
+
+    // Probes every proxy chain of the nodes with a Probe target
+    pub fn check_health(self: &Connecter, conn: Rc<Connecter>) -> Box<Future<Item=(),Error=()>> {
+        let db = self.database();
+        let mut probes: Vec<Box<Future<Item=(),Error=()>>> = vec!();
+        for node in db.nodes.iter().filter_map(|node| node.as_ref()) {
+            let chains = match db.proxy_to[node.id as usize].as_ref() {
+                Some(chains) => chains,
+                None => continue
+            };
+            for chain in chains {
+                // Without Probe only the chains of a closed node are checked
+                if node.probe.is_none() && self.health.is_up(node.id, chain) {
+                    continue
                 }
+                let timeout = match Timeout::new(Duration::from_secs(health::PROBE_TIMEOUT_S), &self.handle) {
+                    Ok(timeout) => timeout,
+                    Err(_) => continue
+                };
+                let timeout = timeout.then(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "probe timed out")));
+                let conn = conn.clone();
+                let id = node.id;
+                let chain = chain.clone();
+                let probe: Box<Future<Item=(),Error=io::Error>> = match node.probe {
+                    Some(ref target) => chain.probe(&self.handle, &self.addresses, target),
+                    None => Box::new(chain.connect(&self.handle, &self.addresses).map(|_| ()))
+                };
+                let probe = probe
+                    .select(timeout)
+                    .then(move |res| {
+                        // The chain works, even if the probe target refuses
+                        let ok = match res {
+                            Ok(_) => true,
+                            Err((ref e,_)) => {
+                                debug!("Probe via {} failed: {}",chain,e);
+                                is_destination_error(e)
+                            }
+                        };
+                        if conn.health.report(id, &chain, ok) {
+                            conn.node_down(id);
+                        }
+                        Ok(())
+                    });
+                probes.push(Box::new(probe));
+            }
+        }
+        Box::new(future::join_all(probes).map(|_| ()))
+    }
+
+    // A peer has sent Close. Its chains are down until it accepts again.
+    pub fn node_closed(self: &Connecter, id: u8) {
+        let db = self.database();
+        match db.proxy_to.get(id as usize) {
+            Some(&Some(ref chains)) => {
+                info!("Node {} is shutting down",id);
+                self.health.close(id, chains);
+                self.node_down(id);
+            },
+            _ => debug!("Node {} is shutting down, no proxies lead to it",id)
+        }
+    }
+
+    // Sessions via a dead node are aborted, so the applications reconnect
+    // through another exit
+    fn node_down(self: &Connecter, id: u8) {
+        let db = self.database();
+        if let Some(ref chains) = db.proxy_to[id as usize] {
+            if self.health.is_dead(id, chains) {
+                let aborted = self.sessions.abort_node(id);
+                warn!("Node {} is dead, {} sessions aborted", id, aborted);
+            }
+        }
+    }
+
+    // First PublicUDP address, which is resolved
+    fn remote_dns_peer(self: &Connecter, db: &Database, id: u8) -> Option<SocketAddr> {
+        match db.nodes[id as usize] {
+            Some(ref node) => node.public_udp.as_ref().and_then(|list| {
+                list.iter().filter_map(|addr| self.addresses.lookup(addr)).next()
+            }),
+            None => None
+        }
+    }
+
+    // Peer messages are only accepted from the hosts of the peers and of the
+    // PublicUDP addresses. The port is not checked, as a node with several
+    // listen sockets sends from any of them.
+    pub fn is_peer(self: &Connecter, from: &SocketAddr) -> bool {
+        let known = |addr: &Address| self.addresses.lookup(addr).is_some_and(|sa| sa.ip() == from.ip());
+        if self.peers.iter().any(&known) {
+            return true
+        }
+        let db = self.database();
+        let found = db.nodes.iter()
+                            .filter_map(|node| node.as_ref())
+                            .filter_map(|node| node.public_udp.as_ref())
+                            .any(|list| list.iter().any(&known));
+        found
+    }
+
+    // Ask the configured RemoteDNS node to resolve the hostname.
+    // Returns None, if the query cannot be sent.
+    fn remote_lookup(self: &Connecter, db: &Database, id: u8, host: &str) -> Option<oneshot::Receiver<RemoteAnswer>> {
+        let peer = match self.remote_dns_peer(db, id) {
+            Some(peer) => peer,
+            None => {
+                warn!("RemoteDNS node {} has no (resolved) PublicUDP address",id);
+                return None
+            }
+        };
+        let mut tx = match self.peer_tx {
+            Some(ref tx) => tx.clone(),
+            None => {
+                debug!("No peer listen address, so no dns query for {}",host);
+                return None
+            }
+        };
+        let mut pending = self.pending_queries.borrow_mut();
+        pending.retain(|_,&mut (_,ref answer_tx)| !answer_tx.is_canceled());
+        // Random ids make it hard to inject answers
+        let mut rng = rand::thread_rng();
+        let mut qid: u32 = rng.gen();
+        while pending.contains_key(&qid) {
+            qid = rng.gen();
+        }
+        let query = match (PeerMessage::DnsQuery { id: qid, host: host.to_string() }).encode() {
+            Some(query) => query,
+            None => {
+                warn!("Hostname {} is too long for a dns query",host);
+                return None
             }
+        };
+        if let Err(e) = tx.try_send((peer, query)) {
+            warn!("Cannot send dns query for {}: {:?}",host,e);
+            return None
         }
-        //println!("{:?}",sa_list);
-        //let sa = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 40002);
-        //sa
-        return sa_list
+        let (answer_tx,answer_rx) = oneshot::channel();
+        pending.insert(qid,(peer,answer_tx));
+        Some(answer_rx)
+    }
+
+    // Called for a DnsAnswer received from a peer. Only the queried peer may answer.
+    pub fn remote_answer(self: &Connecter, from: SocketAddr, id: u32, answer: RemoteAnswer) {
+        let mut pending = self.pending_queries.borrow_mut();
+        let queried = pending.get(&id).map(|&(peer,_)| peer);
+        match queried {
+            Some(peer) if peer.ip() == from.ip() => {
+                if let Some((_,answer_tx)) = pending.remove(&id) {
+                    let _ = answer_tx.send(answer);
+                }
+            },
+            Some(peer) => warn!("Dns answer {} from {}, but {} has been asked",id,from,peer),
+            None => debug!("Late or unknown dns answer {} from {}",id,from)
+        }
+    }
+
+    // Called for a DnsQuery received from a peer.
+    // Resolves the hostname locally and sends back addresses and countries.
+    pub fn answer_remote_query(self: &Connecter, conn: Rc<Connecter>, from: SocketAddr,
+                               id: u32, host: String) -> Box<Future<Item=(),Error=()>> {
+        let tx = match self.peer_tx {
+            Some(ref tx) => tx.clone(),
+            None => return Box::new(future::ok(()))
+        };
+        let mut fqdn = host.clone();
+        fqdn.push('.');
+        Box::new(self.resolver.borrow().lookup_ip(&fqdn)
+            .then(move |res| {
+                let mut ips: Vec<IpAddr> = vec!();
+                let mut countries: Vec<usize> = vec!();
+                match res {
+                    Ok(lookup_ip) => {
+                        ips = lookup_ip.iter().collect();
+                        countries = conn.countries_of(&ips);
+                    },
+                    Err(e) => debug!("Remote query for {} failed: {:?}",host,e)
+                }
+                debug!("Answer {} for {} to {:?}: {:?}",id,host,from,ips);
+                let answer = PeerMessage::DnsAnswer { id, ips, countries };
+                tx.send((from, answer.encode().unwrap())).then(|_| Ok(()))
+            }))
     }
 
     pub fn lookup_transfer(self: &Connecter, source: TcpStream, srr: SocksRequestResponse) -> ResolverFuture {
@@ -223,64 +773,193 @@ impl Connecter {
                     let mut host = srr.hostname().unwrap().to_vec();
                     host.push(b'.');
                     let host = String::from_utf8(host).unwrap();    
-                    (vec![],RFState::Resolve(self.resolver.lookup_ip(&host)))
+                    (vec![],RFState::Resolve(self.resolver.borrow().lookup_ip(&host)))
                 } 
             };
+        let client = source.peer_addr().ok();
+        let db = self.database();
+        // The request is relayed directly, the rule is only asked for the priority
+        let priority = {
+            let host = srr.hostname().map(|h| String::from_utf8_lossy(h).to_lowercase());
+            let host = host.as_deref();
+            let ips: Vec<IpAddr> = srr.ipaddr().into_iter().collect();
+            let ips = if ips.len() > 0 { Some(ips.as_slice()) } else { None };
+            let by_rule = match rules::route(&db.rules, host, ips, None) {
+                Route::Matched(rule) => rule.priority,
+                _ => None
+            };
+            self.priority_of(&db, by_rule, host, srr.port())
+        };
+        let traffic = Traffic::default();
+        let session = self.sessions.register(client, request_target(&Some(srr.clone())), &traffic);
+        if srr.ipaddr().is_none() {
+            session.set_state(SessionState::Resolving);
+        }
         ResolverFuture {
             handle: self.handle.clone(),
             srr: Some(srr),
             state,
+            client,
             source: Some(source),
             destination: None,
-            ips
+            ips,
+            accounting: self.accounting.clone(),
+            opened: Instant::now(),
+            connect_ms: None,
+            traffic,
+            linger: db.linger,
+            limiter: self.limiter(client, None),
+            priority,
+            session,
+            recorded: false
         }
     }
 }
 
 enum State {
     WaitSocksHandshake(SocksHandshake),
+    ResolveHost,
+    WaitCache(oneshot::Receiver<Resolved>),
     Resolve(LookupIpFuture),
+    RemoteResolve(oneshot::Receiver<RemoteAnswer>,Timeout),
     AnalyzeIps(Vec<IpAddr>),
     SelectProxy(Vec<usize>),
+    UseNode(u8),
     NextProxy,
-    Connecting(TcpStreamNew),
-    WaitHandshake(SocksConnectHandshake),
+    // Connect through the upstream proxies of the chain
+    Connecting(Box<Future<Item=TcpStream,Error=io::Error>>),
+    WaitHandshake(Handshake),
+    NextDirectIp,
     ConnectingDirectly(TcpStreamNew),
-    StartTransferDirect(TcpStream),
-    WaitTransfer(Join<Transfer,Transfer>)
+    StartTransferDirect,
+    WaitTransfer(Relay)
 }
 
 pub struct ConnecterFuture {
     handle: Handle,
     state: State,
     connecter: Rc<Connecter>,
+    database: Rc<Database>,
     request: Option<SocksRequestResponse>,
     source: Option<TcpStream>,
+    destination: Option<TcpStream>,
     start: Option<Instant>,
-    sa_list: Option<Vec<SocketAddr>>
+    sa_list: Option<Vec<(u8,ProxyChain)>>,
+    route: Option<Target>,
+    // Priority of the rule, which has matched
+    rule_priority: Option<Priority>,
+    ips: Vec<IpAddr>,
+    known_codes: Option<Vec<usize>>,
+    hostname: Option<String>,
+    cache_leader: Option<String>,
+    preferred_exit: Option<Address>,
+    proxy: Option<(u8,ProxyChain)>,
+    client: Option<SocketAddr>,
+    opened: Instant,
+    requested: Option<Instant>,
+    connect_ms: Option<u64>,
+    country: Option<usize>,
+    exit: Exit,
+    traffic: Traffic,
+    session: SessionHandle,
+    recorded: bool
 }
 
 impl Connecter {
     pub fn resolve_connect_transfer(self: &Connecter,conn: Rc<Connecter>,
                         source: TcpStream) -> ConnecterFuture {
+        let client = source.peer_addr().ok();
         let state = State::WaitSocksHandshake(
             socks_handshake(source)
         );
+        let traffic = Traffic::default();
+        let session = self.sessions.register(client, "-".to_string(), &traffic);
         ConnecterFuture {
             handle: self.handle.clone(),
+            database: conn.database(),
             connecter: conn,
             request: None,
             state: state,
             source: None,
+            destination: None,
             start: None,
-            sa_list: None
+            sa_list: None,
+            route: None,
+            rule_priority: None,
+            ips: vec!(),
+            known_codes: None,
+            hostname: None,
+            cache_leader: None,
+            preferred_exit: None,
+            proxy: None,
+            client,
+            opened: Instant::now(),
+            requested: None,
+            connect_ms: None,
+            country: None,
+            exit: Exit::Unknown,
+            traffic,
+            session,
+            recorded: false
+        }
+    }
+}
+
+impl ConnecterFuture {
+    // Wake up connections waiting for the same hostname
+    fn fail_cache(&mut self) {
+        if let Some(host) = self.cache_leader.take() {
+            self.connecter.cache.fail(&host)
+        }
+    }
+
+    fn priority(&self) -> Option<(Rc<Scheduler>,Priority)> {
+        let port = self.request.as_ref().map_or(0, |req| req.port());
+        self.connecter.priority_of(&self.database, self.rule_priority,
+                                   self.hostname.as_deref(), port)
+    }
+
+    // Called once the success reply has been sent to the client
+    fn connected(&mut self, exit: Exit) {
+        self.session.set_exit(exit.clone());
+        self.session.set_country(self.country);
+        self.session.set_state(SessionState::Relaying);
+        self.exit = exit;
+        if let Some(requested) = self.requested {
+            self.connect_ms = Some(millis(requested.elapsed()));
+        }
+    }
+
+    fn finish(&mut self, reason: CloseReason) {
+        if self.recorded {
+            return
         }
+        self.recorded = true;
+        self.connecter.accounting.record(SessionRecord {
+            client: self.client,
+            target: request_target(&self.request),
+            exit: self.exit.clone(),
+            country: self.country,
+            bytes_up: self.traffic.up(),
+            bytes_down: self.traffic.down(),
+            connect_ms: self.connect_ms,
+            duration: self.opened.elapsed(),
+            reason
+        })
+    }
+}
+
+impl Drop for ConnecterFuture {
+    fn drop(&mut self) {
+        self.fail_cache();
+        self.finish(CloseReason::Aborted)
     }
 }
 
-// The connector determines the best proxy based on country.
+// The connector determines the best proxy based on routing rules first
+// and then based on country.
 // Country is derived based on:
-//   1. xxx.xxx.<COUNTRY CODE>
+//   1. xxx.xxx.<COUNTRY CODE>, unless it is a vanity ccTLD like .io
 //   2. xxx.DOMAIN => loop up
 //   3. country(IP)
 //
@@ -289,14 +968,31 @@ impl Future for ConnecterFuture {
     type Error = io::Error;
 
     fn poll(&mut self) -> Result<Async<Self::Item>, io::Error> {
+        let res = match self.session.poll_killed() {
+            Err(e) => Err(e),
+            Ok(_) => self.poll_state()
+        };
+        match res {
+            Ok(Async::Ready(())) => self.finish(CloseReason::Closed),
+            Err(ref e) => self.finish(CloseReason::Failed(e.to_string())),
+            Ok(Async::NotReady) => ()
+        }
+        res
+    }
+}
+
+impl ConnecterFuture {
+    fn poll_state(&mut self) -> Result<Async<()>, io::Error> {
         loop {
             self.state = match self.state {
                 State::WaitSocksHandshake(ref mut fut) => {
                     let (source,request) = try_ready!(fut.poll());
+                    self.requested = Some(Instant::now());
                     self.source = Some(source);
                     let ip_res  = request.ipaddr();
                     let host_res = request.hostname();
                     self.request = Some(request.clone());
+                    self.session.set_target(request_target(&self.request));
                     match ip_res {
                         Some(ip) => {
                             let ips = vec!(ip);
@@ -304,27 +1000,33 @@ impl Future for ConnecterFuture {
                         },
                         None => {
                             match host_res {
-                                Some(ref host) => {
-                                    let hlen = host.len();
-                                    let ccode = if (hlen > 4) && (host[hlen-3] == b'.') {
-                                            // possible country code
-                                            country_hash(&[host[hlen-2],host[hlen-1]])
-                                        }
-                                        else {
-                                            None
-                                        };
-                                    match ccode {
-                                        None => {
-                                            let mut host = host.to_vec();
-                                            host.push(b'.');
-                                            let host = String::from_utf8(host).unwrap();
-                                            State::Resolve(self.connecter.resolver.lookup_ip(&host))
+                                Some(host) => {
+                                    let hostname = String::from_utf8_lossy(host).to_lowercase();
+                                    // The country of a ccTLD is only used, if no rule
+                                    // needs the addresses of the host
+                                    let ccode = self.database.tld.country_of(&hostname);
+                                    if let Some(code) = ccode {
+                                        debug!("found country code {}",code2country(code));
+                                        self.country = Some(code);
+                                    }
+                                    let codes: Option<Vec<usize>> = ccode.map(|code| vec!(code));
+                                    let route = self.connecter.route(&self.database, Some(&hostname), None,
+                                                                     codes.as_deref());
+                                    self.hostname = Some(hostname);
+                                    match (route,codes) {
+                                        (Route::Matched(rule),_) => {
+                                            self.rule_priority = rule.priority;
+                                            match rule.target {
+                                                Target::Node(id) => State::UseNode(id),
+                                                Target::Direct => {
+                                                    // Needs the addresses to connect to
+                                                    self.route = Some(Target::Direct);
+                                                    State::ResolveHost
+                                                }
+                                            }
                                         },
-                                        Some(code) => {
-                                            debug!("found country code {}",code2country(code));
-                                            let codes: Vec<usize> = vec!(code);
-                                            State::SelectProxy(codes)
-                                        }
+                                        (Route::NoMatch,Some(codes)) => State::SelectProxy(codes),
+                                        (_,_) => State::ResolveHost
                                     }
                                 },
                                 None => panic!()
@@ -332,46 +1034,158 @@ impl Future for ConnecterFuture {
                         }
                     }
                 }
+                State::ResolveHost => {
+                    self.session.set_state(SessionState::Resolving);
+                    let hostname = self.hostname.clone().unwrap();
+                    match self.connecter.cache.lookup(&hostname) {
+                        CacheLookup::Hit(resolved) => {
+                            debug!("Use cached value for {}",hostname);
+                            self.known_codes = Some(resolved.countries);
+                            self.preferred_exit = resolved.exit;
+                            State::AnalyzeIps(resolved.ips)
+                        },
+                        CacheLookup::Wait(rx) => State::WaitCache(rx),
+                        CacheLookup::Miss => {
+                            self.cache_leader = Some(hostname.clone());
+                            match self.database.remote_dns {
+                                Some(id) => {
+                                    // No DNS on a client: let node id resolve it
+                                    match self.connecter.remote_lookup(&self.database,id,&hostname) {
+                                        Some(answer) => {
+                                            let dt = Duration::from_millis(REMOTE_DNS_TIMEOUT_MS);
+                                            let timeout = try!(Timeout::new(dt,&self.handle));
+                                            State::RemoteResolve(answer,timeout)
+                                        },
+                                        None => {
+                                            self.fail_cache();
+                                            State::UseNode(id)
+                                        }
+                                    }
+                                },
+                                None => {
+                                    let host = format!("{}.",hostname);
+                                    State::Resolve(self.connecter.resolver.borrow().lookup_ip(&host))
+                                }
+                            }
+                        }
+                    }
+                },
+                State::WaitCache(ref mut rx) => {
+                    match rx.poll() {
+                        Ok(Async::Ready(resolved)) => {
+                            debug!("Use value resolved by other connection");
+                            self.known_codes = Some(resolved.countries);
+                            self.preferred_exit = resolved.exit;
+                            State::AnalyzeIps(resolved.ips)
+                        },
+                        Ok(Async::NotReady) => return Ok(Async::NotReady),
+                        // The other connection failed, so try on our own
+                        Err(_canceled) => State::ResolveHost
+                    }
+                },
                 State::Resolve(ref mut fut) => {
                     let lookup_ip = try_ready!(fut.poll());
-                    let liter = lookup_ip.iter();
-                    let mut ips = vec!();
-                    for ip in liter {
-                        ips.push(ip);
+                    let ips: Vec<IpAddr> = lookup_ip.iter().collect();
+                    let codes = self.connecter.countries_of(&ips);
+                    if let Some(host) = self.cache_leader.take() {
+                        let resolved = Resolved { ips: ips.clone(), countries: codes.clone(), exit: None };
+                        let valid_until = Instant::now()+Duration::from_secs(DNS_TTL_S);
+                        self.connecter.cache.complete(&host,resolved,valid_until);
                     }
+                    self.known_codes = Some(codes);
                     State::AnalyzeIps(ips)
                 },
-                State::AnalyzeIps(ref ips) => {
-                    let mut codes: Vec<usize> = vec!();
-                    for ip in ips {
-                        let code = self.connecter.determine_country(ip);
-                        match code {
-                            Some(code) => {
-                                if !(codes.contains(&code)) {
-                                    codes.push(code);
-                                };
-                                debug!("IP {:?} -> {:?}",ip,code2country(code))
-                            },
-                            None => 
-                                debug!("IP {:?} -> unknown country",ip)
+                State::RemoteResolve(ref mut answer, ref mut timeout) => {
+                    let id = self.database.remote_dns.unwrap();
+                    match answer.poll() {
+                        Ok(Async::Ready((ips,countries))) => {
+                            debug!("Remote dns by node {}: {:?} {:?}",id,ips,countries);
+                            if ips.len() > 0 {
+                                if let Some(host) = self.cache_leader.take() {
+                                    let resolved = Resolved { ips: ips.clone(), countries: countries.clone(), exit: None };
+                                    let valid_until = Instant::now()+Duration::from_secs(DNS_TTL_S);
+                                    self.connecter.cache.complete(&host,resolved,valid_until);
+                                }
+                                self.known_codes = Some(countries);
+                                State::AnalyzeIps(ips)
+                            }
+                            else {
+                                self.fail_cache();
+                                State::UseNode(id)
+                            }
+                        },
+                        Ok(Async::NotReady) => {
+                            try_ready!(timeout.poll());
+                            warn!("Remote dns by node {} timed out",id);
+                            self.fail_cache();
+                            State::UseNode(id)
+                        },
+                        Err(_canceled) => {
+                            self.fail_cache();
+                            State::UseNode(id)
                         }
+                    }
+                },
+                State::AnalyzeIps(ref ips) => {
+                    // A ccTLD takes precedence over the countries of the addresses
+                    let codes = match (self.country, self.known_codes.take()) {
+                        (Some(code),_) => vec!(code),
+                        (None,Some(codes)) => codes,
+                        (None,None) => self.connecter.countries_of(ips)
                     };
-                    State::SelectProxy(codes)
+                    self.country = codes.first().cloned();
+                    if self.route.is_none() {
+                        let host = self.hostname.as_deref();
+                        if let Route::Matched(rule) = self.connecter.route(&self.database, host,
+                                                                           Some(ips), Some(&codes)) {
+                            self.route = Some(rule.target);
+                            self.rule_priority = rule.priority;
+                        }
+                    }
+                    match self.route {
+                        Some(Target::Direct) => {
+                            self.ips = ips.clone();
+                            State::NextDirectIp
+                        },
+                        Some(Target::Node(id)) => State::UseNode(id),
+                        None => State::SelectProxy(codes)
+                    }
                 },
                 State::SelectProxy(ref codes) => {
-                    let sa_list = self.connecter.select_proxy(codes);
+                    let mut sa_list = self.connecter.select_proxy(&self.database, codes);
+                    // Stay with the exit, which has worked for this host before.
+                    // Proxies are taken from the end of the list.
+                    if let Some(ref exit) = self.preferred_exit {
+                        if let Some(pos) = sa_list.iter().position(|&(_,ref chain)| chain.exit() == exit) {
+                            let sa = sa_list.remove(pos);
+                            sa_list.push(sa);
+                        }
+                    }
                     self.sa_list = Some(sa_list);
                     debug!("{:?}",self.sa_list);
                     State::NextProxy
                 }
+                State::UseNode(id) => {
+                    let sa_list = self.connecter.proxies_of_node(&self.database, id);
+                    self.sa_list = Some(sa_list);
+                    debug!("node {} => {:?}",id,self.sa_list);
+                    State::NextProxy
+                }
                 State::NextProxy => {
                     match self.sa_list {
                         Some(ref mut sa_list) => {
                             let sa = sa_list.pop();
                             match sa {
-                                Some(ref sa) => {
-                                    debug!("Use proxy @ {:?}",*sa);
-                                    State::Connecting(TcpStream::connect(&sa,&self.handle))
+                                Some((id,chain)) => {
+                                    if !self.connecter.breakers.allow(id,&chain) {
+                                        debug!("Skip proxy of node {} @ {}, circuit is open",id,chain);
+                                        continue
+                                    }
+                                    debug!("Use proxy of node {} @ {}",id,chain);
+                                    self.session.set_state(SessionState::Connecting);
+                                    let fut = chain.connect(&self.handle, &self.connecter.addresses);
+                                    self.proxy = Some((id,chain));
+                                    State::Connecting(fut)
                                 },
                                 None =>
                                     return Err(io::Error::new(io::ErrorKind::Other, "no (more) proxy"))
@@ -389,100 +1203,124 @@ impl Future for ConnecterFuture {
                                 Some(ref req) => req.clone(),
                                 None => panic!()
                             };
-                            State::WaitHandshake(socks_connect_handshake(proxy,request))
+                            match self.proxy {
+                                Some((_,ref chain)) => State::WaitHandshake(chain.request(proxy,request)),
+                                None => panic!()
+                            }
                         },
                         Ok(Async::NotReady) => return Ok(Async::NotReady),
-                        Err(_e) => State::NextProxy
+                        Err(e) => {
+                            if let Some((id,ref chain)) = self.proxy {
+                                debug!("Connect to proxy of node {} @ {} failed: {}",id,chain,e);
+                                self.connecter.breakers.failure(id,chain);
+                            }
+                            State::NextProxy
+                        }
                     }
                 },
                 State::WaitHandshake(ref mut fut) => {
                     // Trick from Transfer: Make sure we can write the response !
                     // => This avoids storing the response somewhere.
-                    match self.source {
-                        Some(ref source) => {
-                            let write_ready = source.poll_write().is_ready();
-                            if !write_ready {
-                                return Ok(Async::NotReady)
+                    if let Some(ref source) = self.source {
+                        if !source.poll_write().is_ready() {
+                            return Ok(Async::NotReady)
+                        }
+                    }
+                    let (stream,response) = match fut.poll() {
+                        Ok(Async::Ready(res)) => res,
+                        Ok(Async::NotReady) => return Ok(Async::NotReady),
+                        Err(e) => {
+                            // A refused destination says nothing about the proxies
+                            if let Some((id,ref chain)) = self.proxy {
+                                if is_destination_error(&e) {
+                                    self.connecter.breakers.success(id,chain);
+                                }
+                                else {
+                                    self.connecter.breakers.failure(id,chain);
+                                }
                             }
-                        },
-                        None => ()
+                            return Err(e)
+                        }
                     };
-                    let (stream,response) = try_ready!(fut.poll());
+                    if let Some((id,ref chain)) = self.proxy {
+                        self.connecter.breakers.success(id,chain);
+                    }
                     let dt = match self.start {
                         Some(start) => {
                             let dt = start.elapsed();
-                            let millis = (dt.as_secs()*1000)+((dt.subsec_nanos()/1_000_000) as u64);
+                            let millis = (dt.as_secs()*1000)+(dt.subsec_millis() as u64);
                             Some(millis)
                         },
                         None => None
                     };
                     debug!("Time for connection {:?} ms",dt);
+                    if let (Some(host),Some(&(_,ref chain))) = (self.hostname.as_ref(),self.proxy.as_ref()) {
+                        self.connecter.cache.remember_exit(host,chain.exit().clone());
+                    }
                     // Here can measure the round trip until remote socks server
                     // reports success - still that server can cheat for connect to final destination.
                     let source = self.source.take();
                     let mut source = source.unwrap();
-                    let m = try!(source.write(&response.bytes.to_vec()));
-                    assert_eq!(response.bytes.len(), m);
-
-                    let c1 = Rc::new(source);
-                    let c2 = Rc::new(stream);
+                    let m = try!(source.write(&response));
+                    assert_eq!(response.len(), m);
+                    let exit = match self.proxy {
+                        Some((id,ref chain)) => Exit::Node(id,chain.exit().clone()),
+                        None => Exit::Unknown
+                    };
+                    self.connected(exit);
+                    let limiter = self.connecter.limiter(self.client, self.proxy.as_ref().map(|&(id,_)| id));
+                    let priority = self.priority();
 
-                    let half1 = Transfer::new(c1.clone(), c2.clone());
-                    let half2 = Transfer::new(c2, c1);
-                    State::WaitTransfer(half1.join(half2))
+                    State::WaitTransfer(Relay::new(source, stream, &self.handle,
+                                                   &self.traffic, self.database.linger,
+                                                   limiter, priority))
+                },
+                State::NextDirectIp => {
+                    match self.ips.pop() {
+                        Some(ip) => {
+                            let sa = SocketAddr::new(ip,self.request.as_ref().unwrap().port());
+                            debug!("Connect directly to {:?}",sa);
+                            self.session.set_state(SessionState::Connecting);
+                            State::ConnectingDirectly(TcpStream::connect(&sa,&self.handle))
+                        },
+                        None =>
+                            return Err(io::Error::new(io::ErrorKind::Other, "Cannot connect directly"))
+                    }
                 },
                 State::ConnectingDirectly(ref mut fut) => {
                     match fut.poll() {
                         Ok(Async::Ready(outgoing)) => {
-                            self.start = Some(Instant::now());
-                            let request = match self.request {
-                                Some(ref req) => req.clone(),
-                                None => panic!()
-                            };
-                            State::StartTransferDirect(outgoing)
+                            self.destination = Some(outgoing);
+                            State::StartTransferDirect
                         },
                         Ok(Async::NotReady) => return Ok(Async::NotReady),
-                        Err(_e) => 
-                            return Err(io::Error::new(io::ErrorKind::Other, "Cannot connect directly"))
+                        Err(_e) => State::NextDirectIp
                     }
                 },
-                State::StartTransferDirect(outgoing) => {
+                State::StartTransferDirect => {
                     // Trick from Transfer: Make sure we can write the response !
-                    match self.source {
-                        Some(ref source) => {
-                            let write_ready = source.poll_write().is_ready();
-                            if !write_ready {
-                                return Ok(Async::NotReady)
-                            }
-                        },
-                        None => ()
-                    };
-                    let local = outgoing.local_addr().unwrap();
-                    let x = v5::ATYP_DOMAIN;
-                    let response = match local {
-                        SocketAddr::V4(sa_v4) => {
-                            sa_v4.ip().octets()
-                        },
-                        SocketAddr::V6(sa_v6) => {
-                            sa_v6.ip().octets()
+                    if let Some(ref source) = self.source {
+                        if !source.poll_write().is_ready() {
+                            return Ok(Async::NotReady)
                         }
-                    };
-
-                    let source = mem::replace(&mut self.source, None);
-                    let mut source = source.unwrap();
-                    let m = try!(source.write(&self.request.bytes.to_vec()));
-                    assert_eq!(response.bytes.len(), m);
-
-                    let c1 = Rc::new(source);
-                    let c2 = Rc::new(stream);
+                    }
+                    let outgoing = self.destination.take().unwrap();
+                    let response = socks_success_reply(&try!(outgoing.local_addr()));
 
-                    let half1 = Transfer::new(c1.clone(), c2.clone());
-                    let half2 = Transfer::new(c2, c1);
-                    State::WaitTransfer(half1.join(half2))
+                    let mut source = self.source.take().unwrap();
+                    let m = try!(source.write(&response));
+                    assert_eq!(response.len(), m);
+                    self.connected(Exit::Direct);
+                    let limiter = self.connecter.limiter(self.client, None);
+                    let priority = self.priority();
 
+                    State::WaitTransfer(Relay::new(source, outgoing, &self.handle,
+                                                   &self.traffic, self.database.linger,
+                                                   limiter, priority))
                 },
                 State::WaitTransfer(ref mut fut) => {
-                    let transferred = try_ready!(fut.poll());
+                    // Bytes are counted in traffic
+                    try_ready!(fut.poll());
                     return Ok(Async::Ready(()));
                 }
             }
@@ -0,0 +1,263 @@
+// Control socket for administration.
+//
+// A unix domain socket given by --control accepts one json object per line
+// and answers each with one json line:
+//
+//      {"cmd":"status"}
+//      {"cmd":"peers"}
+//      {"cmd":"list"}
+//      {"cmd":"geoip","host":"www.example.com"}
+//      {"cmd":"kill-session","id":17}
+//      {"cmd":"drain-node","node":3}                no new sessions via node 3
+//      {"cmd":"drain-node","node":3,"drain":false}  undo
+//      {"cmd":"reload-config"}
+//
+// Answers carry "ok":true or "ok":false with an "error". The uservpnctl
+// binary is a client for this socket.
+//
+// The socket is only accessible by the user running uservpn-socks5. A line
+// longer than MAX_LINE closes the connection.
+//
+use std::fs;
+use std::io;
+use std::mem;
+use std::os::unix::fs::PermissionsExt;
+use std::rc::Rc;
+use std::time::Instant;
+use libc;
+use futures::{future, Async, Future, Poll, Stream};
+use tokio_core::reactor::Handle;
+use tokio_io::AsyncRead;
+use tokio_io::io::write_all;
+use tokio_uds::UnixListener;
+use serde_json::{self,Value};
+use accounting::Exit;
+use connecter::Connecter;
+use country::code2country;
+use sessions::SessionInfo;
+
+// Longest accepted request
+const MAX_LINE: usize = 4096;
+
+// Like tokio_io::io::lines, but fails on lines longer than MAX_LINE
+struct Lines<R> {
+    reader: R,
+    buf: Vec<u8>
+}
+
+impl<R: AsyncRead> Stream for Lines<R> {
+    type Item = String;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<String>,io::Error> {
+        loop {
+            if let Some(pos) = self.buf.iter().position(|&b| b == b'\n') {
+                let rest = self.buf.split_off(pos+1);
+                let mut line = mem::replace(&mut self.buf, rest);
+                line.pop();
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+                return match String::from_utf8(line) {
+                    Ok(line) => Ok(Async::Ready(Some(line))),
+                    Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e))
+                }
+            }
+            if self.buf.len() > MAX_LINE {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "request line too long"))
+            }
+            let mut chunk = [0u8; 1024];
+            match self.reader.read(&mut chunk) {
+                // An incomplete last line is ignored
+                Ok(0) => return Ok(Async::Ready(None)),
+                Ok(n) => self.buf.extend_from_slice(&chunk[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                Err(e) => return Err(e)
+            }
+        }
+    }
+}
+
+pub fn serve(path: &str, conn: Rc<Connecter>, config_file: String, handle: &Handle) -> io::Result<()> {
+    let listener = try!(bind(path, handle));
+    info!("Control socket listening on {}", path);
+    let started = Instant::now();
+    let handle2 = handle.clone();
+    let server = listener.incoming().for_each(move |(stream,_)| {
+        let conn = conn.clone();
+        let config_file = config_file.clone();
+        let (reader,writer) = stream.split();
+        let session = Lines { reader, buf: vec!() }
+            .and_then(move |line| {
+                execute(&conn, &config_file, started, &line).map(|answer| {
+                    let mut answer = answer.to_string();
+                    answer.push('\n');
+                    answer
+                })
+            })
+            .fold(writer, |writer, answer| write_all(writer, answer.into_bytes()).map(|(writer,_)| writer))
+            .then(|res| {
+                if let Err(e) = res {
+                    debug!("Control connection failed: {}", e);
+                }
+                Ok(())
+            });
+        handle2.spawn(session);
+        Ok(())
+    })
+    .map_err(|e| error!("Control socket failed: {}", e));
+    handle.spawn(server);
+    Ok(())
+}
+
+// Nobody else may connect, not even before the permissions are set, so
+// the socket is created with the umask 077
+fn bind(path: &str, handle: &Handle) -> io::Result<UnixListener> {
+    // A socket left over by a previous run prevents bind
+    let _ = fs::remove_file(path);
+    let mask = unsafe { libc::umask(0o077) };
+    let listener = UnixListener::bind(path, handle);
+    unsafe { libc::umask(mask) };
+    let listener = try!(listener);
+    try!(fs::set_permissions(path, fs::Permissions::from_mode(0o600)));
+    Ok(listener)
+}
+
+fn ok() -> Value {
+    json!({ "ok": true })
+}
+
+fn error<M: Into<String>>(message: M) -> Value {
+    json!({ "ok": false, "error": message.into() })
+}
+
+pub fn session_json(info: &SessionInfo, now: Instant) -> Value {
+    let node = match info.exit {
+        Exit::Node(id,_) => Some(id),
+        _ => None
+    };
+    json!({
+        "id": info.id,
+        "client": info.client.map(|sa| sa.to_string()),
+        "target": info.target,
+        "exit": info.exit.to_string(),
+        "node": node,
+        "country": info.country.map(code2country),
+        "state": info.state.to_string(),
+        "up": info.bytes_up,
+        "down": info.bytes_down,
+        "age_s": now.duration_since(info.opened).as_secs()
+    })
+}
+
+// Only geoip has to wait for the resolver
+fn execute(conn: &Rc<Connecter>, config_file: &str, started: Instant, line: &str)
+            -> Box<Future<Item=Value,Error=io::Error>> {
+    let request: Value = match serde_json::from_str(line) {
+        Ok(request) => request,
+        Err(e) => return Box::new(future::ok(error(format!("bad request: {}", e))))
+    };
+    debug!("Control request {}", request);
+    if request["cmd"] == "geoip" {
+        let host = match request["host"].as_str() {
+            Some(host) => host.to_lowercase(),
+            None => return Box::new(future::ok(error("host is missing")))
+        };
+        return Box::new(conn.geoip(conn.clone(), &host).then(|res| {
+            Ok(match res {
+                Ok(ips) => {
+                    let ips: Vec<Value> = ips.iter().map(|&(ip,code)| json!({
+                        "ip": ip.to_string(),
+                        "country": code.map(code2country)
+                    })).collect();
+                    json!({ "ok": true, "ips": ips })
+                },
+                Err(e) => error(format!("cannot resolve: {}", e))
+            })
+        }))
+    }
+    Box::new(future::ok(execute_now(conn, config_file, started, &request)))
+}
+
+fn execute_now(conn: &Rc<Connecter>, config_file: &str, started: Instant, request: &Value) -> Value {
+    match request["cmd"].as_str() {
+        Some("status") => {
+            let stats = conn.accounting().statistics();
+            json!({
+                "ok": true,
+                "node": conn.node_id(),
+                "uptime_s": started.elapsed().as_secs(),
+                "active": conn.sessions().list().len(),
+                "sessions": stats.sessions,
+                "failed": stats.failed,
+                "up": stats.bytes_up,
+                "down": stats.bytes_down,
+                "drained": conn.drained()
+            })
+        },
+        Some("peers") => {
+            // There is no ping between peers yet, so the rtt is unknown
+            let peers: Vec<Value> = conn.peers().iter().map(|peer| json!({
+                "peer": peer.to_string(),
+                "address": conn.address_book().lookup(peer).map(|sa| sa.to_string()),
+                "rtt_ms": Value::Null
+            })).collect();
+            json!({ "ok": true, "peers": peers })
+        },
+        Some("list") => {
+            let now = Instant::now();
+            let sessions: Vec<Value> = conn.sessions().list().iter()
+                                           .map(|info| session_json(info, now))
+                                           .collect();
+            json!({ "ok": true, "sessions": sessions })
+        },
+        Some("kill-session") => {
+            match request["id"].as_u64() {
+                Some(id) if conn.sessions().abort(id) => ok(),
+                Some(id) => error(format!("no session {}", id)),
+                None => error("id is missing")
+            }
+        },
+        Some("drain-node") => {
+            let drain = request["drain"].as_bool().unwrap_or(true);
+            match request["node"].as_u64() {
+                Some(id) if conn.database().nodes.get(id as usize).is_some_and(|node| node.is_some()) => {
+                    conn.drain_node(id as u8, drain);
+                    ok()
+                },
+                Some(id) => error(format!("no node {}", id)),
+                None => error("node is missing")
+            }
+        },
+        Some("reload-config") => {
+            match conn.reload_config(conn.clone(), config_file) {
+                Ok(()) => ok(),
+                Err(errors) => {
+                    let errors: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+                    json!({ "ok": false, "error": "config not valid, keep old one", "errors": errors })
+                }
+            }
+        },
+        Some(cmd) => error(format!("unknown command {}", cmd)),
+        None => error("cmd is missing")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+    use tokio_core::reactor::Core;
+
+    #[test]
+    fn socket_is_private() {
+        let core = Core::new().unwrap();
+        let path = env::temp_dir().join(format!("uservpn-test-{}.sock", process::id()));
+        let path = path.to_str().unwrap();
+        let _listener = bind(path, &core.handle()).unwrap();
+        let mode = fs::metadata(path).unwrap().permissions().mode();
+        fs::remove_file(path).unwrap();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
@@ -12,7 +12,7 @@ const COUNTRY: &str = "mmsmcmgbbssbmasccgmtgsggtmpsamncatclbgpmkmlsttngagisnpalc
                        gwcubeaqvetjsrpwnrmrlykygucxbraw";
 
 #[allow(dead_code)]
-static SLOTS_I: &'static [usize] = &[12, 255, 3, 79, 286, 105, 5, 117, 308, 148, 39, 41, 0, 29, 228, 25, 270, 306,
+static SLOTS_I: &[usize] = &[12, 255, 3, 79, 286, 105, 5, 117, 308, 148, 39, 41, 0, 29, 228, 25, 270, 306,
                                      1, 18, 81, 65, 240, 254, 149, 140, 154, 7, 146, 222, 292, 186, 36, 192, 203,
                                      179, 167, 164, 11, 176, 213, 131, 260, 316, 8, 158, 162, 172, 234, 169, 276,
                                      246, 47, 6, 255, 97, 124, 242, 22, 113, 49, 150, 255, 109, 17, 61, 255, 37,
@@ -34,7 +34,7 @@ static SLOTS_I: &'static [usize] = &[12, 255, 3, 79, 286, 105, 5, 117, 308, 148,
                                      120, 107, 161, 184, 255, 255, 255, 255, 86, 64, 84, 174, 255, 166, 204, 50, 68];
 
 #[allow(dead_code)]
-static R: &'static [isize] = &[1, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255,
+static R: &[isize] = &[1, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255,
                                255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255,
                                255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255,
                                255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255,
@@ -66,12 +66,7 @@ pub fn code2country(code: usize) -> String {
 #[allow(dead_code)]
 pub fn country_hash(cn_code: &[u8;2]) -> Option<usize> {
     const T: usize = 178;
-    const NEG_OFFSET: usize = 0;
-    let x: usize = (((cn_code[0] as u16)<<8)+(cn_code[1] as u16)) as usize;
-    if x < NEG_OFFSET {
-        return None;
-    };
-    let val = x - NEG_OFFSET;
+    let val: usize = (((cn_code[0] as u16)<<8)+(cn_code[1] as u16)) as usize;
     let x = val % T;
     let y = val / T;
     let dr = R[y];
@@ -1,210 +1,161 @@
 // This module captures all relevant information from all areas.
 //
 use std::rc::Rc;
-use std::str::FromStr;
 use std::option::Option;
 use std::net::{SocketAddr};
-use ini;
-use country::{country_hash,MAX_COUNTRY_HASH};
+use std::time::Duration;
+use address::Address;
+use config::Config;
+use country::MAX_COUNTRY_HASH;
+use rules::Rule;
+use tld::TldClassifier;
+use dns::DnsConfig;
+use ratelimit::RateLimits;
+use schedule::PriorityConfig;
+use upstream::ProxyChain;
 
 #[derive(Debug)]
 pub struct Node {
-    id: u8,
-    name: String,
-    probe: Option<String>,
-    country_code: Option<usize>,
+    pub id: u8,
+    #[allow(dead_code)]
+    pub name: String,
+    // Target to check the proxy chains to this node
+    pub probe: Option<Address>,
+    // Countries served with their weight
+    pub countries: Vec<(usize,u32)>,
     pub socks5_listen_port: Option<SocketAddr>,
     pub socks_server_ports: Option<Vec<SocketAddr>>,
-    pub public_tcp: Option<Vec<SocketAddr>>,
-    pub public_udp: Option<Vec<SocketAddr>>,
-    pub bind_tcp: Option<Vec<SocketAddr>>
+    pub public_tcp: Option<Vec<Address>>,
+    pub public_udp: Option<Vec<Address>>,
+    pub bind_tcp: Option<Vec<SocketAddr>>,
+    pub dns: Option<DnsConfig>
 }
 
 #[derive(Debug)]
 pub struct Database {
     pub nodes: Vec<Option<Node>>,
-    pub proxy_to: Vec<Option<Vec<SocketAddr>>>,
-    pub country_to_nodes: Vec<Option<Vec<u8>>>
+    pub proxy_to: Vec<Option<Vec<ProxyChain>>>,
+    // Nodes with their weight per country. The default node has weight 0.
+    pub country_to_nodes: Vec<Option<Vec<(u8,u32)>>>,
+    pub rules: Vec<Rule>,
+    pub tld: TldClassifier,
+    pub remote_dns: Option<u8>,
+    pub dns: DnsConfig,
+    pub linger: Duration,
+    // Time for running sessions to finish on quit
+    pub shutdown_timeout: Duration,
+    pub rate_limits: RateLimits,
+    pub priority: PriorityConfig
 }
 
 #[allow(dead_code)]
 impl Database {
-    pub fn new() -> Rc<Database> {
+    fn empty() -> Database {
         let mut db = Database {
             nodes: vec!(),   // Array of Nodes set to None
             proxy_to: vec!(),
-            country_to_nodes: vec!()
+            country_to_nodes: vec!(),
+            rules: vec!(),
+            tld: TldClassifier::new(),
+            remote_dns: None,
+            dns: DnsConfig::default(),
+            linger: Duration::from_secs(60),
+            shutdown_timeout: Duration::from_secs(30),
+            rate_limits: RateLimits::default(),
+            priority: PriorityConfig::default()
         };
-        for _i in 0..255 {
+        // Node ids are 0..255
+        for _i in 0..256 {
             db.nodes.push(None);
             db.proxy_to.push(None);
         };
         for _i in 1..MAX_COUNTRY_HASH {
             db.country_to_nodes.push(None);
         }
-        Rc::new(db)
+        db
     }
 
-    pub fn read_from_ini(&mut self, config: ini::Ini) -> Result<(),(&str)> {
-        // Print parsed config file for debugging
-        if true {
-            for (sec, prop) in config.iter() {
-                debug!("Section: {:?}", *sec);
-                for (k, v) in prop.iter() {
-                    debug!("   {}:{}", *k, *v);
+    // The config has been validated, so all node ids are known
+    pub fn from_config(config: Config) -> Rc<Database> {
+        let mut db = Database::empty();
+        for node in config.nodes {
+            for &(ch,weight) in &node.countries {
+                db.country_to_nodes[ch].get_or_insert(vec!()).push((node.id,weight));
+            }
+            let id = node.id as usize;
+            db.nodes[id] = Some(node);
+        }
+        for (to_id,chains) in config.proxy_to {
+            db.proxy_to[to_id as usize] = Some(chains);
+        }
+        if let Some(id) = config.default_node {
+            for nodes in db.country_to_nodes.iter_mut() {
+                let nodes = nodes.get_or_insert(vec!());
+                if !nodes.iter().any(|&(nid,_)| nid == id) {
+                    nodes.push((id,0));
                 }
             }
         }
+        db.rules = config.rules;
+        db.tld = config.tld;
+        db.remote_dns = config.remote_dns;
+        db.dns = config.dns;
+        db.linger = config.linger;
+        db.shutdown_timeout = config.shutdown_timeout;
+        db.rate_limits = config.rate_limits;
+        db.priority = config.priority;
+        Rc::new(db)
+    }
 
-        match config.section(Some("Nodes")) { 
-            Some(section) =>  {
-                for (k,v) in section.iter() { 
-                    debug!("NODE  {}:{}", *k, *v);
-                    let nodename: &str = &v.to_string();
-                    let id = k.to_string();
-                    let id = u8::from_str(&id).unwrap();
-                    match config.section(Some(nodename)) {
-                        Some(ref node_section) => {
-                            let mut new_node = Node {
-                                id,
-                                name: nodename.to_string(),
-                                probe: None,
-                                country_code: None,
-                                socks5_listen_port: None,
-                                socks_server_ports: None,
-                                public_tcp: None,
-                                public_udp: None,
-                                bind_tcp : None
-                            };
-                            for (k,v) in node_section.iter() {
-                                match k.as_ref() {
-                                    "Probe" => {
-                                        new_node.probe = Some(v.to_string());
-                                    },
-                                    "Socks5Address" => {
-                                        match v.to_string().parse::<SocketAddr>() {
-                                            Err(e) => return Err("Socks5Address is wrong"),
-                                            Ok(sa) => new_node.socks5_listen_port = Some(sa)
-                                        }
-                                    },
-                                    "PublicTCP" => {
-                                        let flds = v.split(",");
-                                        let mut sa_list: Vec<SocketAddr> = vec!();
-                                        for add in flds {
-                                            match add.parse::<SocketAddr>() {
-                                                Err(e) => return Err("TCPAddress is wrong"),
-                                                Ok(sa) => sa_list.push(sa)
-                                            }
-                                        }
-                                        if sa_list.len() > 0 {
-                                            new_node.public_tcp = Some(sa_list)
-                                        }
-                                    },
-                                    "BindTCP" => {
-                                        let flds = v.split(",");
-                                        let mut sa_list: Vec<SocketAddr> = vec!();
-                                        for add in flds {
-                                            match add.parse::<SocketAddr>() {
-                                                Err(e) => return Err("TCPAddress is wrong"),
-                                                Ok(sa) => sa_list.push(sa)
-                                            }
-                                        }
-                                        if sa_list.len() > 0 {
-                                            new_node.bind_tcp = Some(sa_list)
-                                        }
-                                    },
-                                    "PublicUDP" => {
-                                        let flds = v.split(",");
-                                        let mut sa_list: Vec<SocketAddr> = vec!();
-                                        for add in flds {
-                                            match add.parse::<SocketAddr>() {
-                                                Err(e) => return Err("UDPAddress is wrong"),
-                                                Ok(sa) => sa_list.push(sa)
-                                            }
-                                        }
-                                        if sa_list.len() > 0 {
-                                            new_node.public_udp = Some(sa_list)
-                                        }
-                                    },
-                                    "SocksServerPorts" => {
-                                        let flds = v.split(",");
-                                        let mut sa_list: Vec<SocketAddr> = vec!();
-                                        for add in flds {
-                                            match add.parse::<SocketAddr>() {
-                                                Err(e) => return Err("UDPAddress is wrong"),
-                                                Ok(sa) => sa_list.push(sa)
-                                            }
-                                        }
-                                        if sa_list.len() > 0 {
-                                            new_node.socks_server_ports = Some(sa_list)
-                                        }
-                                    },
-                                    "Country" if v.len() == 2 => {
-                                        let country = v.to_string().to_lowercase().into_bytes();
-                                        let code = country_hash(&[country[0],country[1]]);
-                                        if let Some(ch) = code {
-                                            new_node.country_code = Some(ch);
-                                            if None == self.country_to_nodes[ch] {
-                                                self.country_to_nodes[ch] = Some(vec!())
-                                            }
-                                            if let Some(ref mut id_list) = self.country_to_nodes[ch] {
-                                                id_list.push(id)
-                                            }
-                                        }
-                                    },
-                                    _ if k.contains("SocksProxy->") => {
-                                        let to_id = k[12..].to_string();
-                                        let to_id = u8::from_str(&to_id).unwrap();
-                                        let mut sa_list: Vec<SocketAddr> = vec!();
-                                        let split = v.split(",");
-                                        for add in split {
-                                            match add.parse::<SocketAddr>() {
-                                                Err(e) => return Err("SocksProxy Address is wrong"),
-                                                Ok(sa) => {
-                                                    //println!("Node {} -> {:?}",to_id,&sa);
-                                                    sa_list.push(sa)
-                                                }
-                                            }    
-                                        }
-                                        if sa_list.len() > 0 {
-                                            self.proxy_to[to_id as usize] = Some(sa_list)
-                                        }
-                                    },
-                                    _ => {
-                                        debug!("UNKNOWN NODESECTION  {}:{}", *k, *v);
-                                    }
-                                };
-                            }
-                            self.nodes[id as usize] = Some(new_node)
-                        },
-                        None => return Err("Cannot find node section")
-                    }
-                };
-            },
-            None => return Err("No [Nodes] section in config-file")
-        };
-        match config.section(Some("Self")) { 
-            Some(section) =>  {
-                    for (k,v) in section.iter() { 
-                        debug!("Self:  {}:{}", *k, *v);
-                        match &k[..] {
-                            "Default" => {
-                                let id = u8::from_str(v).unwrap();
-                                for i in 0..self.country_to_nodes.len() {
-                                    if self.country_to_nodes[i].is_none() {
-                                        self.country_to_nodes[i] = Some(vec![id]);
-                                    }
-                                    else {
-                                        self.country_to_nodes[i].as_mut().unwrap().push(id);
-                                    }
-                                }
-                            },
-                            _ => ()
-                        }
-                    }
-            },
-            None => return Err("No [Self] section in config-file")
+    // Name servers for the given node: its own DNS entry or the [DNS] section
+    pub fn dns_for(&self, id: u8) -> &DnsConfig {
+        if let Some(ref node) = self.nodes[id as usize] {
+            if let Some(ref dns) = node.dns {
+                return dns
+            }
         }
-        Ok(())
+        &self.dns
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::{validate,Section};
+    use rules::Target;
+
+    fn database(nodes: &[(&str,&str)], node_entries: &[(&str,&str)], rules: &[(&str,&str)]) -> Rc<Database> {
+        let section = |name: &str, entries: &[(&str,&str)]| Section {
+            name: name.to_string(),
+            entries: entries.iter().map(|&(k,v)| (k.to_string(), v.to_string())).collect()
+        };
+        let config = validate(&[section("Nodes", nodes),
+                                section("Last", node_entries),
+                                section("Other", &[]),
+                                section("Self", &[]),
+                                section("Rules", rules)]).unwrap();
+        Database::from_config(config)
+    }
+
+    #[test]
+    fn node_255_in_nodes() {
+        let db = database(&[("255","Last")], &[("DNS","9.9.9.9")], &[]);
+        assert_eq!(db.nodes[255].as_ref().unwrap().name, "Last");
+        assert_eq!(db.dns_for(255).servers.len(), 1);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn proxy_to_node_255() {
+        let db = database(&[("1","Last"), ("255","Other")],
+                          &[("SocksProxy->255","1.2.3.4:40000")], &[]);
+        assert_eq!(db.proxy_to[255].as_ref().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn rule_to_node_255() {
+        let db = database(&[("255","Last")], &[], &[("10","*.example.org -> node 255")]);
+        assert_eq!(db.rules[0].target, Target::Node(255));
+        assert!(db.nodes[255].is_some());
+        assert!(db.proxy_to[255].is_none());
+    }
+}
@@ -0,0 +1,165 @@
+// Upstream name servers used by the resolver.
+//
+// Configured in the [DNS] section and overridden per node with a DNS key
+// in the node's section:
+//
+//      [DNS]
+//      Servers = udp://1.1.1.1:53,tcp://9.9.9.9
+//      System = no
+//
+// System = yes reads /etc/resolv.conf instead. Without any configuration
+// Google's public DNS is used. A bare address means udp and port 53.
+// trust-dns-resolver 0.8 knows neither DNS over TLS nor DNS over https, so
+// tls:// and https:// servers are rejected when the config is read.
+//
+use std::io;
+use std::net::{IpAddr,SocketAddr};
+use trust_dns_resolver::config::*;
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum DnsServer {
+    Udp(SocketAddr),
+    Tcp(SocketAddr)
+}
+
+#[derive(Debug,Clone,Default)]
+pub struct DnsConfig {
+    pub servers: Vec<DnsServer>,
+    pub system: bool
+}
+
+fn parse_addr(s: &str, default_port: u16) -> Result<SocketAddr,String> {
+    if let Ok(sa) = s.parse::<SocketAddr>() {
+        return Ok(sa)
+    }
+    match s.trim_matches(|c| c == '[' || c == ']').parse::<IpAddr>() {
+        Ok(ip) => Ok(SocketAddr::new(ip,default_port)),
+        Err(_) => Err(format!("bad name server address <{}>", s))
+    }
+}
+
+impl DnsServer {
+    pub fn parse(s: &str) -> Result<DnsServer,String> {
+        let s = s.trim();
+        if s.starts_with("udp://") {
+            Ok(DnsServer::Udp(parse_addr(&s[6..],53)?))
+        }
+        else if s.starts_with("tcp://") {
+            Ok(DnsServer::Tcp(parse_addr(&s[6..],53)?))
+        }
+        else if s.starts_with("tls://") || s.starts_with("https://") {
+            Err(format!("name server <{}> is not supported by the resolver, use udp:// or tcp://", s))
+        }
+        else if s.contains("://") {
+            Err(format!("unknown name server protocol <{}>", s))
+        }
+        else {
+            Ok(DnsServer::Udp(parse_addr(s,53)?))
+        }
+    }
+}
+
+impl DnsConfig {
+    pub fn is_configured(&self) -> bool {
+        self.system || self.servers.len() > 0
+    }
+
+    pub fn read_servers(&mut self, value: &str) -> Result<(),String> {
+        for s in value.split(",").filter(|s| s.trim().len() > 0) {
+            self.servers.push(DnsServer::parse(s)?);
+        }
+        Ok(())
+    }
+
+    pub fn read_system(&mut self, value: &str) -> Result<(),String> {
+        match value.trim().to_lowercase().as_ref() {
+            "yes" | "true" | "1" => self.system = true,
+            "no" | "false" | "0" => self.system = false,
+            _ => return Err(format!("System expects yes or no, got <{}>", value))
+        }
+        Ok(())
+    }
+
+    pub fn resolver_config(&self) -> io::Result<(ResolverConfig,ResolverOpts)> {
+        if self.system {
+            return read_system_conf()
+        }
+        if self.servers.len() == 0 {
+            return Ok((ResolverConfig::default(),ResolverOpts::default()))
+        }
+        let name_servers: Vec<NameServerConfig> = self.servers.iter().map(name_server).collect();
+        Ok((ResolverConfig::from_parts(None,vec!(),name_servers),ResolverOpts::default()))
+    }
+}
+
+#[cfg(unix)]
+fn read_system_conf() -> io::Result<(ResolverConfig,ResolverOpts)> {
+    ::trust_dns_resolver::system_conf::read_system_conf()
+}
+
+#[cfg(not(unix))]
+fn read_system_conf() -> io::Result<(ResolverConfig,ResolverOpts)> {
+    Err(io::Error::new(io::ErrorKind::Other, "System = yes is only supported on unix"))
+}
+
+fn name_server(server: &DnsServer) -> NameServerConfig {
+    match *server {
+        DnsServer::Udp(sa) => NameServerConfig { socket_addr: sa, protocol: Protocol::Udp },
+        DnsServer::Tcp(sa) => NameServerConfig { socket_addr: sa, protocol: Protocol::Tcp }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr,UdpSocket};
+    use std::thread;
+    use tokio_core::reactor::Core;
+    use trust_dns_resolver::ResolverFuture;
+
+    // Answers the first A query with 10.1.2.3
+    fn stub_server() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            let (n,from) = socket.recv_from(&mut buf).unwrap();
+            // The question follows the 12 byte header: labels, 0, type, class
+            let mut end = 12;
+            while buf[end] != 0 {
+                end += buf[end] as usize + 1;
+            }
+            end += 5;
+            assert!(end <= n);
+            let mut reply: Vec<u8> = vec!();
+            reply.extend_from_slice(&buf[0..2]);
+            reply.extend_from_slice(&[0x81, 0x80, 0, 1, 0, 1, 0, 0, 0, 0]);
+            reply.extend_from_slice(&buf[12..end]);
+            reply.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 10, 1, 2, 3]);
+            socket.send_to(&reply, from).unwrap();
+        });
+        addr
+    }
+
+    #[test]
+    fn parse_servers() {
+        assert_eq!(DnsServer::parse("9.9.9.9"), Ok(DnsServer::Udp("9.9.9.9:53".parse().unwrap())));
+        assert_eq!(DnsServer::parse("tcp://[::1]"), Ok(DnsServer::Tcp("[::1]:53".parse().unwrap())));
+        assert!(DnsServer::parse("https://dns.google/dns-query").is_err());
+        assert!(DnsServer::parse("tls://1.1.1.1#cloudflare-dns.com").is_err());
+        assert!(DnsServer::parse("quic://1.1.1.1").is_err());
+        assert!(DnsServer::parse("udp://example.com").is_err());
+    }
+
+    #[test]
+    fn resolve_via_configured_server() {
+        let mut dns = DnsConfig::default();
+        dns.read_servers(&format!("udp://{}", stub_server())).unwrap();
+        let (config,opts) = dns.resolver_config().unwrap();
+        let mut core = Core::new().unwrap();
+        let resolver = ResolverFuture::new(config, opts, &core.handle());
+        let lookup = core.run(resolver.lookup_ip("stub.test.")).unwrap();
+        let ips: Vec<IpAddr> = lookup.iter().collect();
+        assert_eq!(ips, vec!(IpAddr::V4(Ipv4Addr::new(10,1,2,3))));
+    }
+}
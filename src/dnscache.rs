@@ -0,0 +1,95 @@
+// Cache of resolved hostnames shared by all connections.
+//
+// Browsers open many connections to the same host. Only the first one
+// resolves the hostname and locates the addresses. Concurrent requests for
+// the same hostname wait for that result instead of doing the same again
+// (like the promises map of the pony Chooser).
+//
+// The proxy, which worked for a host, is remembered as well. So further
+// connections to that host use the same exit as long as the entry is valid.
+//
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Instant;
+use futures::sync::oneshot;
+use address::Address;
+
+#[derive(Debug,Clone)]
+pub struct Resolved {
+    pub ips: Vec<IpAddr>,
+    pub countries: Vec<usize>,
+    pub exit: Option<Address>
+}
+
+struct CacheEntry {
+    resolved: Resolved,
+    valid_until: Instant
+}
+
+pub enum CacheLookup {
+    // Valid entry found
+    Hit(Resolved),
+    // Another connection is resolving this host
+    Wait(oneshot::Receiver<Resolved>),
+    // Caller has to resolve and then call complete() or fail()
+    Miss
+}
+
+pub struct DnsCache {
+    entries: RefCell<HashMap<String,CacheEntry>>,
+    waiting: RefCell<HashMap<String,Vec<oneshot::Sender<Resolved>>>>
+}
+
+impl DnsCache {
+    pub fn new() -> DnsCache {
+        DnsCache {
+            entries: RefCell::new(HashMap::new()),
+            waiting: RefCell::new(HashMap::new())
+        }
+    }
+
+    pub fn lookup(&self, host: &str) -> CacheLookup {
+        if let Some(entry) = self.entries.borrow().get(host) {
+            if entry.valid_until > Instant::now() {
+                return CacheLookup::Hit(entry.resolved.clone())
+            }
+        }
+        let mut waiting = self.waiting.borrow_mut();
+        if let Some(waiters) = waiting.get_mut(host) {
+            let (tx,rx) = oneshot::channel();
+            waiters.push(tx);
+            return CacheLookup::Wait(rx)
+        }
+        waiting.insert(host.to_string(),vec!());
+        CacheLookup::Miss
+    }
+
+    pub fn complete(&self, host: &str, resolved: Resolved, valid_until: Instant) {
+        if let Some(waiters) = self.waiting.borrow_mut().remove(host) {
+            for tx in waiters {
+                let _ = tx.send(resolved.clone());
+            }
+        }
+        let now = Instant::now();
+        let mut entries = self.entries.borrow_mut();
+        entries.retain(|_,entry| entry.valid_until > now);
+        entries.insert(host.to_string(),CacheEntry { resolved, valid_until });
+    }
+
+    // Waiting connections are woken up and have to resolve on their own
+    pub fn fail(&self, host: &str) {
+        self.waiting.borrow_mut().remove(host);
+    }
+
+    // Running lookups are not affected
+    pub fn clear(&self) {
+        self.entries.borrow_mut().clear();
+    }
+
+    pub fn remember_exit(&self, host: &str, exit: Address) {
+        if let Some(entry) = self.entries.borrow_mut().get_mut(host) {
+            entry.resolved.exit = Some(exit);
+        }
+    }
+}
@@ -0,0 +1,96 @@
+// Health of the proxy chains to the nodes.
+//
+// A node's Probe key names a target, which is connected to through every
+// proxy chain of that node every PROBE_INTERVAL_S seconds:
+//
+//      [Frankfurt]
+//      Probe = www.google.com:80
+//
+// A chain is marked down after FAIL_THRESHOLD failed probes in a row and
+// skipped by select_proxy, until a probe succeeds again. A node with all
+// chains down is dead.
+//
+// The chains of nodes without Probe are up, unless the node has announced
+// its shutdown with a Close message. Then they are probed by connecting
+// to the node's socks server, until it accepts again.
+//
+use std::cell::RefCell;
+use std::collections::HashMap;
+use upstream::{ProxyChain,retain_configured};
+
+// Time between two probes of a chain
+pub const PROBE_INTERVAL_S: u64 = 30;
+
+// A probe fails, if the handshake takes longer
+pub const PROBE_TIMEOUT_S: u64 = 10;
+
+// Failed probes in a row, which mark a chain down
+const FAIL_THRESHOLD: u32 = 2;
+
+#[derive(Default)]
+struct ChainHealth {
+    failures: u32,
+    down: bool
+}
+
+pub struct Health {
+    chains: RefCell<HashMap<(u8,ProxyChain),ChainHealth>>
+}
+
+impl Health {
+    pub fn new() -> Health {
+        Health {
+            chains: RefCell::new(HashMap::new())
+        }
+    }
+
+    pub fn is_up(&self, id: u8, chain: &ProxyChain) -> bool {
+        match self.chains.borrow().get(&(id,chain.clone())) {
+            Some(health) => !health.down,
+            None => true
+        }
+    }
+
+    // Returns true, if the chain has just been marked down
+    pub fn report(&self, id: u8, chain: &ProxyChain, ok: bool) -> bool {
+        let mut chains = self.chains.borrow_mut();
+        let health = chains.entry((id,chain.clone())).or_default();
+        if ok {
+            if health.down {
+                info!("Proxy {} of node {} is up again", chain, id);
+            }
+            health.failures = 0;
+            health.down = false;
+            false
+        }
+        else {
+            health.failures += 1;
+            debug!("Probe via {} of node {} failed {} times", chain, id, health.failures);
+            if !health.down && health.failures >= FAIL_THRESHOLD {
+                warn!("Proxy {} of node {} is down", chain, id);
+                health.down = true;
+                return true
+            }
+            false
+        }
+    }
+
+    // The node has announced its shutdown
+    pub fn close(&self, id: u8, chains: &[ProxyChain]) {
+        let mut health = self.chains.borrow_mut();
+        for chain in chains {
+            let entry = health.entry((id,chain.clone())).or_default();
+            entry.failures = FAIL_THRESHOLD;
+            entry.down = true;
+        }
+    }
+
+    pub fn is_dead(&self, id: u8, chains: &[ProxyChain]) -> bool {
+        chains.iter().all(|chain| !self.is_up(id, chain))
+    }
+
+    // A reload forgets the probe results of removed chains
+    pub fn retain(&self, proxy_to: &[Option<Vec<ProxyChain>>]) {
+        retain_configured(&mut *self.chains.borrow_mut(), proxy_to)
+    }
+}
@@ -1,9 +1,15 @@
+// The crate is written in the 2015 idiom: try!, trait objects without dyn,
+// `&(ref a, ref b)` patterns, len() > 0 and manual prefix stripping.
+#![allow(bare_trait_objects, deprecated)]
+#![allow(clippy::len_zero, clippy::manual_strip, clippy::needless_borrowed_reference,
+         clippy::large_enum_variant, clippy::type_complexity, clippy::io_other_error,
+         clippy::redundant_field_names)]
+
 #[macro_use]
 extern crate log;
 extern crate env_logger;
 #[macro_use]
 extern crate futures;
-#[macro_use]
 extern crate tokio_core;
 extern crate tokio_io;
 extern crate tokio_timer;
@@ -11,14 +17,32 @@ extern crate trust_dns_resolver;
 #[macro_use]
 extern crate clap;
 extern crate ini;
+extern crate yaml_rust;
 extern crate csv;
+extern crate regex;
+extern crate rand;
+#[macro_use]
+extern crate serde_json;
 extern crate socksv5_future;
 extern crate termion;
 extern crate tui;
 extern crate tui_logger;
-
-use std::io;
+#[cfg(unix)]
+extern crate tokio_signal;
+#[cfg(unix)]
+extern crate tokio_uds;
+#[cfg(unix)]
+extern crate libc;
+#[cfg(all(target_os = "linux", feature = "splice"))]
+extern crate mio;
+
+use std::io::{self,Write};
+use std::env;
+use std::fs::{self,File};
+use std::process;
 use std::str::FromStr;
+use std::cmp;
+use std::collections::HashMap;
 use std::cell::RefCell;
 //use std::io::{self, Read, Write};
 //use std::net::{Shutdown, IpAddr};
@@ -33,13 +57,13 @@ use std::{thread, time};
 use std::sync;
 
 use log::LevelFilter;
-use futures::{Future, Stream, Sink};
-use futures::sync::mpsc;
+use futures::{AsyncSink, Future, Stream, Sink};
+use futures::future::{self, Either, Shared};
+use futures::sync::{mpsc,oneshot};
 use futures::sync::mpsc::{Sender, Receiver};
 use futures::stream::{SplitSink,SplitStream};
 use tokio_core::net::{TcpListener, UdpSocket};
-use tokio_core::reactor::{Core, Interval};
-use ini::Ini;
+use tokio_core::reactor::{Core, Interval, Timeout};
 use socksv5_future::socks_handshake;
 use termion::event;
 use termion::event::Key;
@@ -48,9 +72,7 @@ use tui::Terminal;
 use tui::layout::{Direction, Group, Rect, Size};
 use tui::style::{Color, Style, Modifier};
 use tui::backend::MouseBackend;
-use tui::buffer::Buffer;
-use tui::widgets::{Axis, BarChart, Block, Borders, Chart, Dataset, Gauge, Item, List, Marker,
-                   Paragraph, Row, SelectableList, Sparkline, Table, Tabs, Widget};
+use tui::widgets::{Block, Borders, Row, Table, Tabs, Widget};
 use tui_logger::*;
 
 mod message;
@@ -58,6 +80,24 @@ mod transfer;
 mod country;
 mod connecter;
 mod database;
+mod config;
+mod rules;
+mod region;
+mod tld;
+mod dns;
+mod dnscache;
+mod upstream;
+mod address;
+mod health;
+mod breaker;
+mod sessions;
+#[cfg(unix)]
+mod control;
+mod accounting;
+mod ratelimit;
+mod schedule;
+#[cfg(all(target_os = "linux", feature = "splice"))]
+mod splice;
 
 //
 // The following streams/futures are executed:
@@ -83,10 +123,125 @@ struct TuiApp {
     size: Rect,
     state: Vec<TuiWidgetState>,
     dispatcher: Rc<RefCell<Dispatcher<event::Event>>>,
-    selected_tab: Rc<RefCell<usize>>
+    selected_tab: Rc<RefCell<usize>>,
+    breakers: breaker::Breakers,
+    sessions: sessions::Sessions,
+    connections: Rc<RefCell<ConnectionsView>>
 }
 
-fn main() {
+const CONNECTION_COLUMNS: [&str; 8] = ["Id","Target","Country","Exit","Up","Down","Rate","Age"];
+
+// Sort order, selection and transfer rates of the Connections tab
+struct ConnectionsView {
+    sort_by: usize,
+    reverse: bool,
+    selected: Option<u64>,
+    // Session ids in the displayed order
+    order: Vec<u64>,
+    // Per session id the bytes at the last sample and the rate in bytes/s
+    rates: HashMap<u64,(u64,u64)>,
+    sampled: Instant
+}
+
+impl ConnectionsView {
+    fn new() -> ConnectionsView {
+        ConnectionsView {
+            sort_by: 0,
+            reverse: false,
+            selected: None,
+            order: vec!(),
+            rates: HashMap::new(),
+            sampled: Instant::now()
+        }
+    }
+
+    fn rate(&self, id: u64) -> u64 {
+        self.rates.get(&id).map_or(0, |&(_,rate)| rate)
+    }
+
+    // Rates are updated at most once per second
+    fn sample(&mut self, list: &[sessions::SessionInfo]) {
+        let elapsed = self.sampled.elapsed();
+        if elapsed < Duration::from_secs(1) {
+            return
+        }
+        let ms = cmp::max(accounting::millis(elapsed), 1);
+        let mut rates = HashMap::new();
+        for info in list {
+            let bytes = info.bytes_up + info.bytes_down;
+            let before = self.rates.get(&info.id).map_or(0, |&(bytes,_)| bytes);
+            rates.insert(info.id, (bytes, bytes.saturating_sub(before)*1000/ms));
+        }
+        self.rates = rates;
+        self.sampled = Instant::now();
+    }
+
+    fn sort(&mut self, list: &mut [sessions::SessionInfo]) {
+        list.sort_by(|a,b| {
+            let order = match self.sort_by {
+                1 => a.target.cmp(&b.target),
+                2 => a.country.map(country::code2country).cmp(&b.country.map(country::code2country)),
+                3 => a.exit.to_string().cmp(&b.exit.to_string()),
+                4 => a.bytes_up.cmp(&b.bytes_up),
+                5 => a.bytes_down.cmp(&b.bytes_down),
+                6 => self.rate(a.id).cmp(&self.rate(b.id)),
+                7 => b.opened.cmp(&a.opened),
+                _ => a.id.cmp(&b.id)
+            };
+            if self.reverse { order.reverse() } else { order }
+        });
+        self.order = list.iter().map(|info| info.id).collect();
+        if self.selected.is_none_or(|id| !self.order.contains(&id)) {
+            self.selected = self.order.first().cloned();
+        }
+    }
+
+    fn handle(&mut self, evt: &event::Event, sessions: &sessions::Sessions) -> bool {
+        let pos = self.selected.and_then(|id| self.order.iter().position(|&x| x == id));
+        match *evt {
+            event::Event::Key(Key::Up) => {
+                if let Some(p) = pos {
+                    if p > 0 {
+                        self.selected = Some(self.order[p-1]);
+                    }
+                }
+            },
+            event::Event::Key(Key::Down) => {
+                match pos {
+                    Some(p) if p+1 < self.order.len() => self.selected = Some(self.order[p+1]),
+                    Some(_) => (),
+                    None => self.selected = self.order.first().cloned()
+                }
+            },
+            event::Event::Key(Key::Char('s')) => self.sort_by = (self.sort_by+1) % CONNECTION_COLUMNS.len(),
+            event::Event::Key(Key::Char('r')) => self.reverse = !self.reverse,
+            event::Event::Key(Key::Char('k')) => {
+                if let Some(id) = self.selected {
+                    sessions.abort(id);
+                }
+            },
+            _ => return false
+        }
+        true
+    }
+}
+
+fn human_bytes(n: u64) -> String {
+    if n < 1024 {
+        format!("{}B",n)
+    }
+    else if n < 1024*1024 {
+        format!("{:.1}K",n as f64/1024.0)
+    }
+    else if n < 1024*1024*1024 {
+        format!("{:.1}M",n as f64/(1024.0*1024.0))
+    }
+    else {
+        format!("{:.1}G",n as f64/(1024.0*1024.0*1024.0))
+    }
+}
+
+fn init_tui_logger() {
     init_logger(LevelFilter::Trace).unwrap();
     set_default_level(LevelFilter::Trace);
     set_hot_buffer_depth(10000);
@@ -117,37 +272,69 @@ fn main() {
     set_level_for_target("tokio_reactor::background", LevelFilter::Warn);
     set_level_for_target("tokio_threadpool::builder", LevelFilter::Warn);
     set_level_for_target("tokio_threadpool::pool", LevelFilter::Warn);
+}
+
+// Without TUI the log goes to stderr. RUST_LOG overrides the level given by -d.
+fn init_env_logger(debug: u64) {
+    let level = match debug {
+        0 => "info",
+        1 => "debug",
+        _ => "trace"
+    };
+    let filter = env::var("RUST_LOG").unwrap_or(level.to_string());
+    let mut builder = env_logger::LogBuilder::new();
+    builder.parse(&filter);
+    builder.init().unwrap();
+}
 
+fn main() {
     let matches = clap_app!(uservpn_socks5 =>
         (version: crate_version!())
         (author: "Jochen Kiemes <jochen@kiemes.de>")
         (about: "Multi-server multi-client vpn")
-        (@arg CONFIG: -c --config +takes_value   "Sets a custom config file")
+        (@arg CONFIG: -c --config +takes_value   "Sets a custom config file (.ini or .yaml)")
         (@arg debug:  -d ...                     "Sets the level of debugging information")
         (@arg listen: -l --listen +takes_value   "Listening addresses for peers <ip:port,...>")
-        (@arg peers:  -p --peers  +takes_value   "List of known peer servers <ip:port,...>")
+        (@arg peers:  -p --peers  +takes_value   "List of known peer servers <ip:port or host:port,...>")
+        (@arg control: --control +takes_value    "Unix socket for the control api <path>")
         (@arg id: -i --id +takes_value +required "Unique ID of this instance <id>=0..255")
+        (@arg daemon: --daemon                   "Runs without TUI and logs to stderr, same as --no-tui")
+        (@arg no_tui: --("no-tui")               "Runs without TUI and logs to stderr")
+        (@arg pidfile: --pidfile +takes_value    "Writes the process id to <path>")
     ).get_matches();
 
-    let mut database = database::Database::new();
+    let headless = matches.is_present("daemon") || matches.is_present("no_tui");
+    if headless {
+        init_env_logger(matches.occurrences_of("debug"));
+    }
+    else {
+        init_tui_logger();
+    }
 
-    let config_file = matches.value_of("config").unwrap_or("config.ini");
-    let config = Ini::load_from_file(config_file).unwrap();
-    if let Err(s) = Rc::get_mut(&mut database).unwrap().read_from_ini(config) {
-        error!("{}",s);
-        return
+    let config_file = matches.value_of("CONFIG").unwrap_or("config.ini");
+    let database = match config::load(config_file) {
+        Ok(config) => database::Database::from_config(config),
+        Err(errors) => {
+            // The tui logger is not shown yet, so the errors go to stderr
+            for e in errors {
+                error!("{}",e);
+                if !headless {
+                    eprintln!("{}",e);
+                }
+            }
+            process::exit(1)
+        }
     };
 
     let node_id = matches.value_of("id").unwrap();
-    let node_id = u8::from_str(&node_id).unwrap();
+    let node_id = u8::from_str(node_id).unwrap();
 
-    let mut peer_list: Vec<SocketAddr> = Vec::new();
+    let mut peer_list: Vec<address::Address> = Vec::new();
     if let Some(peers) = matches.value_of("peers") {
         for ad in peers.split(",") {
-            let a = ad.clone();
-            match ad.parse::<SocketAddr>() {
+            match address::Address::parse(ad) {
                 Ok(x)  => peer_list.push(x),
-                Err(x) => error!("Ignore peer <{}> => {}",a,x),
+                Err(x) => error!("Ignore peer <{}> => {}",ad,x),
             }
         }
     }
@@ -156,7 +343,7 @@ fn main() {
     let mut listen_list: Vec<SocketAddr> = Vec::new();
     if let Some(listen) = matches.value_of("listen") {
         for ad in listen.split(",") {
-            let a = ad.clone();
+            let a = ad;
             match ad.parse::<SocketAddr>() {
                 Ok(x)  => listen_list.push(x),
                 Err(x) => error!("Ignore listen address <{}> => {}",a,x),
@@ -172,11 +359,88 @@ fn main() {
     //let buffer = Rc::new(RefCell::new(vec![0; 64 * 1024]));
     let handle = lp.handle();
 
-    let mut connecter = connecter::Connecter::new(handle.clone(),database.clone());
+    let mut connecter = connecter::Connecter::new(handle.clone(),database.clone(),node_id);
     connecter.read_dbip();
 
-    if false {
-        let my_id = 1;
+    // The udp_sender is connected to a mspc, which receives messages compatible to MessageCodec.
+    // Control messages like dns queries use tx, bulk data uses bulk_tx. Messages of tx
+    // are always sent first.
+    let (tx, rx): (Sender<(SocketAddr, Vec<u8>)>,Receiver<(SocketAddr, Vec<u8>)>) = mpsc::channel(100);
+    let (bulk_tx, bulk_rx): (Sender<(SocketAddr, Vec<u8>)>,Receiver<(SocketAddr, Vec<u8>)>) = mpsc::channel(100);
+    // Without a listen address nothing sends the messages, so remote dns
+    // queries are not even tried
+    if listen_list.len() > 0 {
+        connecter.set_peer_sender(tx.clone());
+    }
+    else if let Some(id) = database.remote_dns {
+        warn!("RemoteDNS is set, but there is no peer listen address (-l). Hostnames go to node {} unresolved.",id);
+    }
+    connecter.set_peers(peer_list);
+    let connecter = Rc::new(connecter);
+
+    // Hostnames of peers, public addresses and proxies are resolved at start
+    // and then periodically. Changed addresses are used by new connections.
+    {
+        let conn = connecter.clone();
+        let handle2 = handle.clone();
+        let refresh = Interval::new_at(Instant::now(),
+                                       Duration::from_secs(address::REFRESH_INTERVAL_S),&handle).unwrap()
+                        .for_each(move |_| {
+                            handle2.spawn(conn.refresh_addresses(conn.clone()));
+                            Ok(())
+                        })
+                        .then( |_| { Ok(())});
+        handle.spawn(refresh);
+    }
+
+    // Proxy chains of nodes with a Probe target are checked periodically
+    {
+        let conn = connecter.clone();
+        let handle2 = handle.clone();
+        let checker = Interval::new_at(Instant::now()+Duration::from_secs(health::PROBE_INTERVAL_S),
+                                       Duration::from_secs(health::PROBE_INTERVAL_S),&handle).unwrap()
+                        .for_each(move |_| {
+                            handle2.spawn(conn.check_health(conn.clone()));
+                            Ok(())
+                        })
+                        .then( |_| { Ok(())});
+        handle.spawn(checker);
+    }
+
+    // On SIGHUP the config file is read again. New connections use the new
+    // database, running ones finish with the old one. Listen addresses are
+    // not changed by a reload.
+    #[cfg(unix)]
+    {
+        let conn = connecter.clone();
+        let config_file = config_file.to_string();
+        let reload = tokio_signal::unix::Signal::new(tokio_signal::unix::SIGHUP,&handle)
+                        .flatten_stream()
+                        .for_each(move |_| {
+                            info!("SIGHUP: reload {}",config_file);
+                            if let Err(errors) = conn.reload_config(conn.clone(),&config_file) {
+                                for e in errors {
+                                    error!("{}",e);
+                                }
+                                error!("Reload of {} failed, keep running with old config",config_file);
+                            }
+                            Ok(())
+                        })
+                        .map_err(|e| error!("SIGHUP handler failed: {}",e));
+        handle.spawn(reload);
+    }
+
+    #[cfg(unix)]
+    {
+        if let Some(path) = matches.value_of("control") {
+            if let Err(e) = control::serve(path, connecter.clone(), config_file.to_string(), &handle) {
+                error!("Cannot open control socket {}: {}", path, e);
+            }
+        }
+    }
+
+    if listen_list.len() > 0 {
+        let my_id = node_id;
         let secret = 1;
         let mut udp_sinks:   Vec<SplitSink<tokio_core::net::UdpFramed<message::MessageCodec>>> = vec![]; 
         let mut udp_streams: Vec<SplitStream<tokio_core::net::UdpFramed<message::MessageCodec>>> = vec![]; 
@@ -188,39 +452,90 @@ fn main() {
             udp_streams.push(udp_stream);
         }
 
-        // The udp_sender is connected to a mspc, which receives messages compatible to MessageCodec.
-        //
         // If several udp sockets are available, then use round robin for sending.
         //
-        let (tx, rx): (Sender<(SocketAddr, Vec<u8>)>,Receiver<(SocketAddr, Vec<u8>)>) = mpsc::channel(100);
         info!("number of listen sockets = {}",udp_sinks.len());
         let counter: Vec<usize> = vec![0];
-        let counter = RefCell::new(counter);
-        let udp_sender = rx.for_each(move |msg| {
-            let mut counter = counter.borrow_mut();
-            info!("{:?}",counter);
-            let mut cnt = counter[0];
-            cnt = if cnt == udp_sinks.len()-1 {
-                0
-            } else { cnt + 1 };
-            counter[0] = cnt;
-
-            let res = udp_sinks[cnt].poll_complete();
-            match res {
-                Ok(_)  => info!("poll_complete is ok"),
-                Err(e) => {
+        let counter = Rc::new(RefCell::new(counter));
+        let udp_sinks = Rc::new(RefCell::new(udp_sinks));
+        // Messages wait for tokens of the global rate limit. As for_each
+        // waits for the returned future, the following messages wait too.
+        // The limiter is taken per message, so a reload applies at once.
+        let conn_sender = connecter.clone();
+        let handle_sender = handle.clone();
+        let udp_sender = schedule::PriorityStream::new(rx, bulk_rx).for_each(move |msg| {
+            let wait = match conn_sender.peer_limiter().map(|l| l.reserve(msg.1.len())) {
+                Some(delay) if delay > Duration::from_secs(0) => {
+                    trace!("Delay peer message for {:?}",delay);
+                    Either::A(Timeout::new(delay,&handle_sender).unwrap().map_err(|_| ()))
+                },
+                _ => Either::B(future::ok(()))
+            };
+            let counter = counter.clone();
+            let udp_sinks = udp_sinks.clone();
+            wait.then(move |_| {
+                let mut counter = counter.borrow_mut();
+                let mut udp_sinks = udp_sinks.borrow_mut();
+                let mut cnt = counter[0];
+                cnt = if cnt == udp_sinks.len()-1 {
+                    0
+                } else { cnt + 1 };
+                counter[0] = cnt;
+
+                let to = msg.0;
+                let report = |e: io::Error| {
                     match e.kind() {
-                        AddrNotAvailable => panic!("Peer listen address like 127.0.0.1 does not work"),
-                        _ => info!("{:?}",e)
+                        AddrNotAvailable => error!("Cannot send to {}, a peer listen address like 127.0.0.1 does not work",to),
+                        _ => warn!("Cannot send to {}: {}",to,e)
                     }
+                };
+                // A message still in the buffer is sent first
+                if let Err(e) = udp_sinks[cnt].poll_complete() {
+                    report(e);
                 }
-            };
-            let _res = udp_sinks[cnt].start_send(msg).unwrap();
-            // The stream will stop on `Err`, so we need to return `Ok`.
-            Ok(())
+                match udp_sinks[cnt].start_send(msg) {
+                    Ok(AsyncSink::Ready) => (),
+                    Ok(AsyncSink::NotReady(_)) => warn!("Socket busy, drop message to {}",to),
+                    Err(e) => report(e)
+                }
+                // Flush now, otherwise the message waits for the next one
+                if let Err(e) = udp_sinks[cnt].poll_complete() {
+                    report(e);
+                }
+                // The stream will stop on `Err`, so we need to return `Ok`.
+                Ok(())
+            })
         });
         handle.spawn(udp_sender);
 
+        // Messages received from peers
+        for udp_stream in udp_streams {
+            let handle2 = handle.clone();
+            let conn2 = connecter.clone();
+            let receiver = udp_stream.for_each(move |(from,buf)| {
+                // Answering anybody would make this an open dns reflector
+                if !conn2.is_peer(&from) {
+                    debug!("Ignore message from {}, which is no peer",from);
+                    return Ok(())
+                }
+                match message::PeerMessage::decode(&buf) {
+                    Some(message::PeerMessage::DnsQuery { id, host }) => {
+                        handle2.spawn(conn2.answer_remote_query(conn2.clone(),from,id,host))
+                    },
+                    Some(message::PeerMessage::DnsAnswer { id, ips, countries }) => {
+                        conn2.remote_answer(from,id,(ips,countries))
+                    },
+                    Some(message::PeerMessage::Close { node }) => {
+                        conn2.node_closed(node)
+                    },
+                    None => debug!("Unknown message from {:?}",from)
+                }
+                Ok(())
+            })
+            .then( |_| { Ok(())});
+            handle.spawn(receiver);
+        }
+
         // The duty of the initiator is trying to connect to the peers 
         // unless connection is established. 
         // Connect means to send a Hello message with info about self.
@@ -232,17 +547,21 @@ fn main() {
         //
         // initiator helds a future to be waited for
         if false {
-            let peer_list2 = peer_list.clone();
+            let conn2 = connecter.clone();
             let tx2 = tx.clone();
             let handle2 = handle.clone();
             let initiator = Interval::new_at(Instant::now()+Duration::new(1,0),
                                         Duration::new(10,0),&handle).unwrap()
                                 .for_each(move |_| {
-                                    for ad in &peer_list2 {
+                                    for peer in conn2.peers() {
+                                        let ad = match conn2.address_book().lookup(peer) {
+                                            Some(ad) => ad,
+                                            None => continue
+                                        };
                                         info!("Send Init to {}",ad);
                                         let thread_tx = tx2.clone();
                                         let buf: Vec<u8> = vec![0;10];
-                                        let msg = (ad.clone(),buf);
+                                        let msg = (ad,buf);
                                         handle2.spawn(thread_tx.send(msg)
                                                         .then( |_| { Ok(())}));
                                     };
@@ -253,15 +572,20 @@ fn main() {
         }
 
         if false {
+            let conn2 = connecter.clone();
             let handle2 = handle.clone();
             let communicator = Interval::new_at(Instant::now()+Duration::new(1,0),
                                         Duration::new(1,0),&handle).unwrap()
                                 .for_each(move |_| {
-                                    for ad in &peer_list {
+                                    for peer in conn2.peers() {
+                                        let ad = match conn2.address_book().lookup(peer) {
+                                            Some(ad) => ad,
+                                            None => continue
+                                        };
                                         info!("Send Data to {}",ad);
-                                        let thread_tx = tx.clone();
+                                        let thread_tx = bulk_tx.clone();
                                         let buf: Vec<u8> = vec![0;10];
-                                        let msg = (ad.clone(),buf);
+                                        let msg = (ad,buf);
                                         handle2.spawn(thread_tx.send(msg)
                                                         .then( |_| { Ok(())}));
                                     };
@@ -272,6 +596,10 @@ fn main() {
         }
     }
 
+    // On quit the socks listeners stop accepting, running sessions continue
+    let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+    let shutdown = shutdown_rx.shared();
+
     if let Some(ref node) = database.nodes[node_id as usize] {
         // Construct a future representing our server. This future processes all
         // incoming connections and spawns a new task for each client which will do
@@ -282,7 +610,6 @@ fn main() {
         // future representing the completion of handling that client. This future
         // itself is then *spawned* onto the event loop to ensure that it can
         // progress concurrently with all other connections.
-        let connecter = Rc::new(connecter);
         if let Some(addr) = node.socks5_listen_port {
             info!("Listening for socks5 proxy connections on {:?}", addr);
             let handle2 = handle.clone();
@@ -302,7 +629,7 @@ fn main() {
                 Ok(())
             })
             .then( |_| { Ok(())});
-            handle.spawn(server)
+            handle.spawn(until_shutdown(server,&shutdown))
         }
 
         if let Some(ref vec_addr) = node.socks_server_ports {
@@ -310,7 +637,7 @@ fn main() {
                 debug!("Listening for socks5 connections on {:?}", addr);
                 let handle2 = handle.clone();
                 let conn2 = connecter.clone();
-                let listener = TcpListener::bind(&addr, &handle2).unwrap();
+                let listener = TcpListener::bind(addr, &handle2).unwrap();
                 let server = listener.incoming().for_each(move |(socket, _addr)| {
                     let c = conn2.clone();
                     handle2.spawn(
@@ -332,11 +659,110 @@ fn main() {
                     Ok(())
                 })
                 .then( |_| { Ok(())});
-                handle.spawn(server)
+                handle.spawn(until_shutdown(server,&shutdown))
             }
         }
     }
 
+    // The event loop runs until quit is requested by q in the TUI or by a
+    // signal. Both start the same shutdown.
+    let (quit_tx, quit_rx) = mpsc::unbounded::<&'static str>();
+    #[cfg(unix)]
+    {
+        for &(signal,name) in &[(tokio_signal::unix::SIGTERM,"SIGTERM"),(tokio_signal::unix::SIGINT,"SIGINT")] {
+            let quit_tx = quit_tx.clone();
+            let handler = tokio_signal::unix::Signal::new(signal,&handle)
+                            .flatten_stream()
+                            .for_each(move |_| {
+                                let _ = quit_tx.unbounded_send(name);
+                                Ok(())
+                            })
+                            .map_err(move |e| error!("{} handler failed: {}",name,e));
+            handle.spawn(handler);
+        }
+    }
+
+    let pidfile = matches.value_of("pidfile");
+    if let Some(path) = pidfile {
+        if let Err(e) = File::create(path).and_then(|mut f| writeln!(f, "{}", process::id())) {
+            error!("Cannot write pid file {}: {}",path,e);
+        }
+    }
+
+    let tui = if headless {
+        None
+    }
+    else {
+        Some(start_tui(connecter.breakers(), connecter.sessions(), quit_tx.clone()))
+    };
+
+    let (reason,quit_rx) = match lp.run(quit_rx.into_future()) {
+        Ok((Some(reason),rest)) => (reason,rest),
+        Ok((None,rest)) | Err(((),rest)) => ("end of input",rest)
+    };
+    info!("Quit by {}",reason);
+
+    if let Some((tui_tx,tui_thread)) = tui {
+        let _ = tui_tx.send(Event::Quit);
+        let _ = tui_thread.join();
+        move_events();
+    }
+
+    // Stop accepting, tell the peers and give the running sessions time to
+    // finish. The TUI is gone by now, so only another SIGTERM or SIGINT
+    // ends the wait early.
+    let _ = shutdown_tx.send(());
+    connecter.notify_close();
+    let sessions = connecter.sessions();
+    let timeout = connecter.database().shutdown_timeout;
+    if sessions.count() > 0 {
+        info!("Wait up to {}s for {} sessions to finish",timeout.as_secs(),sessions.count());
+        if !headless {
+            eprintln!("Wait up to {}s for {} sessions to finish, Ctrl-C to exit now",
+                      timeout.as_secs(),sessions.count());
+        }
+    }
+    let remaining = sessions.clone();
+    // The first tick is delayed, so the close messages get sent
+    let finished = Interval::new_at(Instant::now()+Duration::from_millis(100),
+                                    Duration::from_millis(100),&handle).unwrap()
+                    .take_while(move |_| Ok(remaining.count() > 0))
+                    .for_each(|_| Ok(()))
+                    .map(|_| "all sessions finished")
+                    .map_err(|_| ());
+    let deadline = Timeout::new(timeout,&handle).unwrap()
+                    .map(|_| "shutdown timeout")
+                    .map_err(|_| ());
+    let again = quit_rx.into_future()
+                    .map(|(reason,_)| reason.unwrap_or("end of input"))
+                    .map_err(|_| ());
+    let drain: Vec<Box<Future<Item=&'static str,Error=()>>> =
+                    vec!(Box::new(finished),Box::new(deadline),Box::new(again));
+    if let Ok((reason,_,_)) = lp.run(future::select_all(drain)) {
+        let left = sessions.count();
+        if left > 0 {
+            warn!("Exit by {}, {} sessions are closed",reason,left);
+        }
+        else {
+            info!("Exit by {}",reason);
+        }
+    }
+    if let Some(path) = pidfile {
+        let _ = fs::remove_file(path);
+    }
+}
+
+// The server stops, when shutdown is signalled
+fn until_shutdown<F>(server: F, shutdown: &Shared<oneshot::Receiver<()>>) -> Box<Future<Item=(),Error=()>>
+        where F: Future<Item=(),Error=()> + 'static {
+    Box::new(server.select(shutdown.clone().then(|_| Ok(())))
+                   .then(|_| Ok(())))
+}
+
+// Runs the TUI in its own threads. The returned sender stops the TUI.
+fn start_tui(breakers: breaker::Breakers, sessions: sessions::Sessions,
+             quit_tx: mpsc::UnboundedSender<&'static str>)
+                -> (sync::mpsc::Sender<Event>, thread::JoinHandle<()>) {
     let backend = MouseBackend::new().unwrap();
     let mut terminal = Terminal::new(backend).unwrap();
     terminal.clear().unwrap();
@@ -348,8 +774,6 @@ fn main() {
     let input_tx = tx.clone();
     let timer_tx = tx.clone();
 
-    let (quit_tx, quit_rx) = futures::sync::oneshot::channel::<bool>();
-
     thread::spawn(move || {
             let one_second = time::Duration::from_millis(1_000);
             loop {
@@ -362,28 +786,39 @@ fn main() {
     thread::spawn(move || {
             let stdin = io::stdin();
             for c in stdin.events() {
-                let evt = c.unwrap();
+                // Reading fails, if stdin is closed
+                let evt = match c {
+                    Ok(evt) => evt,
+                    Err(_) => break
+                };
+                // q starts the shutdown like a signal, main then stops the TUI
                 if evt == event::Event::Key(event::Key::Char('q')) {
-                    input_tx.send(Event::Quit).unwrap();
+                    let _ = quit_tx.unbounded_send("q");
                     break;
                 }
-                else {
-                    input_tx.send(Event::Input(evt)).unwrap();
+                if input_tx.send(Event::Input(evt)).is_err() {
+                    break;
                 }
             }
         });
 
-    thread::spawn(move || {
+    let tui_thread = thread::spawn(move || {
         let mut app = TuiApp {
             size: terminal.size().unwrap(),
             state: vec![],
             dispatcher: Rc::new(RefCell::new(Dispatcher::<event::Event>::new())),
-            selected_tab: Rc::new(RefCell::new(0))
+            selected_tab: Rc::new(RefCell::new(0)),
+            breakers,
+            sessions,
+            connections: Rc::new(RefCell::new(ConnectionsView::new()))
         };
         loop {
             move_events();
         
-            let evt = rx.recv().unwrap();
+            let evt = match rx.recv() {
+                Ok(evt) => evt,
+                Err(_) => break
+            };
             trace!("{:?}",evt);
             let mut redraw = false;
             match evt {
@@ -391,12 +826,9 @@ fn main() {
                     if app.dispatcher.borrow_mut().dispatch(&input) {
                         redraw = true;
                     }
-                    else if input == event::Event::Key(event::Key::Char('q')) {
-                    }
                 },
                 Event::Tick => redraw = true,
                 Event::Quit => {
-                    quit_tx.send(true).unwrap();
                     drop(rx);
                     break;
                 }
@@ -413,14 +845,11 @@ fn main() {
         terminal.show_cursor().unwrap();
         terminal.clear().unwrap();
     });
-
-    lp.run(quit_rx);
-
-    move_events();
+    (tx, tui_thread)
 }
 
 fn draw(t: &mut Terminal<MouseBackend>, app: &mut TuiApp) -> Result<(), io::Error> {
-    let tabs = vec!["ALL","Tab4","Tab5","Tab6"];
+    let tabs = vec!["ALL","Proxies","Connections","Tab6"];
     let sel = *app.selected_tab.borrow();
 
     // add commands to dispatcher
@@ -441,6 +870,12 @@ fn draw(t: &mut Terminal<MouseBackend>, app: &mut TuiApp) -> Result<(), io::Erro
                 false
             }
         });
+    if tabs[sel] == "Connections" {
+        let view = app.connections.clone();
+        let sessions = app.sessions.clone();
+        app.dispatcher.borrow_mut().add_listener(
+            move |evt| view.borrow_mut().handle(evt, &sessions));
+    }
     Group::default()
         .direction(Direction::Vertical)
         .sizes(&[Size::Fixed(3), Size::Min(10)])
@@ -453,6 +888,64 @@ fn draw(t: &mut Terminal<MouseBackend>, app: &mut TuiApp) -> Result<(), io::Erro
                 .select(sel)
                 .render(t, &chunks[0]);
             match tabs[sel] {
+                "Proxies" => {
+                    // Only chains, which have failed, are listed
+                    let open_style = Style::default().fg(Color::Red);
+                    let half_open_style = Style::default().fg(Color::Yellow);
+                    let closed_style = Style::default();
+                    let circuits = app.breakers.snapshot();
+                    let rows = circuits.iter().map(|&(id,ref chain,state,failures)| {
+                        let style = match state {
+                            breaker::CircuitState::Open(_) => &open_style,
+                            breaker::CircuitState::HalfOpen(_) => &half_open_style,
+                            breaker::CircuitState::Closed => &closed_style
+                        };
+                        let cells = vec![id.to_string(), chain.clone(), state.to_string(), failures.to_string()];
+                        Row::StyledData(cells.into_iter(), style)
+                    });
+                    Table::new(["Node","Proxy","Circuit","Failures"].iter(), rows)
+                        .block(Block::default().title("Circuits").borders(Borders::ALL))
+                        .header_style(Style::default().fg(Color::Yellow))
+                        .widths(&[6, 60, 12, 8])
+                        .render(t, &chunks[1]);
+                },
+                "Connections" => {
+                    let now = Instant::now();
+                    let mut list = app.sessions.list();
+                    let mut view = app.connections.borrow_mut();
+                    view.sample(&list);
+                    view.sort(&mut list);
+                    let selected_style = Style::default().modifier(Modifier::Invert);
+                    let normal_style = Style::default();
+                    let rows: Vec<(Vec<String>,&Style)> = list.iter().map(|info| {
+                        let cells = vec![
+                            info.id.to_string(),
+                            info.target.clone(),
+                            info.country.map_or("-".to_string(), country::code2country),
+                            info.exit.to_string(),
+                            human_bytes(info.bytes_up),
+                            human_bytes(info.bytes_down),
+                            format!("{}/s",human_bytes(view.rate(info.id))),
+                            format!("{}s",now.duration_since(info.opened).as_secs())
+                        ];
+                        let style = if view.selected == Some(info.id) { &selected_style } else { &normal_style };
+                        (cells,style)
+                    }).collect();
+                    let header: Vec<String> = CONNECTION_COLUMNS.iter().enumerate().map(|(i,title)| {
+                        match (i == view.sort_by, view.reverse) {
+                            (true,false) => format!("{}+",title),
+                            (true,true) => format!("{}-",title),
+                            _ => title.to_string()
+                        }
+                    }).collect();
+                    Table::new(header.iter(), rows.into_iter().map(|(cells,style)| Row::StyledData(cells.into_iter(), style)))
+                        .block(Block::default()
+                                .title("Sessions (Up/Down select, s sort, r reverse, k kill)")
+                                .borders(Borders::ALL))
+                        .header_style(Style::default().fg(Color::Yellow))
+                        .widths(&[6, 36, 7, 24, 8, 8, 10, 7])
+                        .render(t, &chunks[1]);
+                },
                 _ => {
                     while app.state.len() <= sel {
                         app.state.push(TuiWidgetState::new());
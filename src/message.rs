@@ -1,6 +1,6 @@
 
 use std::io;
-use std::net::SocketAddr;
+use std::net::{SocketAddr,IpAddr,Ipv4Addr,Ipv6Addr};
 use tokio_core::net::UdpCodec;
 
 // The message tail is watermarked with 3*64 bits.
@@ -53,6 +53,7 @@ pub struct MessageInfo { // Can be placed after payload with few separating wast
 	pub pad1: u16,
 }
 
+#[allow(dead_code)]
 pub struct MessageCodec {
     pub my_id:  u8, // This contains my own id. If UdpMessage matches, then payload will be decrypted.
     pub secret: u8  // Shared secret for encryption and decryption
@@ -74,3 +75,191 @@ impl UdpCodec for MessageCodec {
 	}
 }
 
+// Payload of the messages exchanged between peers.
+//
+// Layout: type (u8), id (u32 BE), then type specific fields:
+//   DnsQuery:  hostname length (u8), hostname. Longer hostnames cannot be sent.
+//   DnsAnswer: number of ips (u8), each as 4 or 16 (u8) + octets,
+//              number of country codes (u8), each as u16 BE
+//   Close:     node id of the sender (u8), the id is 0
+pub const MSG_DNS_QUERY: u8 = 1;
+pub const MSG_DNS_ANSWER: u8 = 2;
+pub const MSG_CLOSE: u8 = 3;
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum PeerMessage {
+	DnsQuery { id: u32, host: String },
+	DnsAnswer { id: u32, ips: Vec<IpAddr>, countries: Vec<usize> },
+	// The sender is shutting down
+	Close { node: u8 },
+}
+
+fn push_u32(buf: &mut Vec<u8>, x: u32) {
+	buf.push((x >> 24) as u8);
+	buf.push((x >> 16) as u8);
+	buf.push((x >> 8) as u8);
+	buf.push(x as u8);
+}
+
+impl PeerMessage {
+	// None, if a hostname is too long for the message
+	pub fn encode(&self) -> Option<Vec<u8>> {
+		let mut buf: Vec<u8> = vec!();
+		match *self {
+			PeerMessage::DnsQuery { id, ref host } => {
+				buf.push(MSG_DNS_QUERY);
+				push_u32(&mut buf, id);
+				if host.len() > 255 {
+					return None
+				}
+				buf.push(host.len() as u8);
+				buf.extend_from_slice(host.as_bytes());
+			},
+			PeerMessage::DnsAnswer { id, ref ips, ref countries } => {
+				buf.push(MSG_DNS_ANSWER);
+				push_u32(&mut buf, id);
+				let n = ips.len().min(255);
+				buf.push(n as u8);
+				for ip in &ips[..n] {
+					match *ip {
+						IpAddr::V4(ref ipv4) => {
+							buf.push(4);
+							buf.extend_from_slice(&ipv4.octets());
+						},
+						IpAddr::V6(ref ipv6) => {
+							buf.push(16);
+							buf.extend_from_slice(&ipv6.octets());
+						}
+					}
+				}
+				let n = countries.len().min(255);
+				buf.push(n as u8);
+				for code in &countries[..n] {
+					buf.push((*code >> 8) as u8);
+					buf.push(*code as u8);
+				}
+			},
+			PeerMessage::Close { node } => {
+				buf.push(MSG_CLOSE);
+				push_u32(&mut buf, 0);
+				buf.push(node);
+			}
+		}
+		Some(buf)
+	}
+
+	pub fn decode(buf: &[u8]) -> Option<PeerMessage> {
+		if buf.len() < 6 {
+			return None
+		}
+		let id = ((buf[1] as u32) << 24) | ((buf[2] as u32) << 16)
+			   | ((buf[3] as u32) << 8) | (buf[4] as u32);
+		let mut pos = 5;
+		match buf[0] {
+			MSG_DNS_QUERY => {
+				let n = buf[pos] as usize;
+				pos += 1;
+				if buf.len() < pos+n {
+					return None
+				}
+				match String::from_utf8(buf[pos..pos+n].to_vec()) {
+					Ok(host) => Some(PeerMessage::DnsQuery { id, host }),
+					Err(_) => None
+				}
+			},
+			MSG_DNS_ANSWER => {
+				let n = buf[pos] as usize;
+				pos += 1;
+				let mut ips: Vec<IpAddr> = vec!();
+				for _ in 0..n {
+					if buf.len() <= pos {
+						return None
+					}
+					let len = buf[pos] as usize;
+					pos += 1;
+					if buf.len() < pos+len {
+						return None
+					}
+					let octets = &buf[pos..pos+len];
+					match len {
+						4 => ips.push(IpAddr::V4(Ipv4Addr::new(octets[0],octets[1],octets[2],octets[3]))),
+						16 => {
+							let mut o = [0u8; 16];
+							o.copy_from_slice(octets);
+							ips.push(IpAddr::V6(Ipv6Addr::from(o)))
+						},
+						_ => return None
+					}
+					pos += len;
+				}
+				if buf.len() <= pos {
+					return None
+				}
+				let n = buf[pos] as usize;
+				pos += 1;
+				if buf.len() < pos+2*n {
+					return None
+				}
+				let mut countries: Vec<usize> = vec!();
+				for i in 0..n {
+					countries.push(((buf[pos+2*i] as usize) << 8) | (buf[pos+2*i+1] as usize));
+				}
+				Some(PeerMessage::DnsAnswer { id, ips, countries })
+			},
+			MSG_CLOSE => Some(PeerMessage::Close { node: buf[pos] }),
+			_ => None
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn messages() -> Vec<PeerMessage> {
+		vec!(
+			PeerMessage::DnsQuery { id: 0xdeadbeef, host: "www.example.com".to_string() },
+			PeerMessage::DnsAnswer {
+				id: 7,
+				ips: vec!("1.2.3.4".parse().unwrap(), "2001:db8::1".parse().unwrap()),
+				countries: vec!(0, 300)
+			},
+			PeerMessage::DnsAnswer { id: 8, ips: vec!(), countries: vec!() },
+			PeerMessage::Close { node: 3 }
+		)
+	}
+
+	#[test]
+	fn decode_inverts_encode() {
+		for msg in messages() {
+			assert_eq!(PeerMessage::decode(&msg.encode().unwrap()), Some(msg));
+		}
+	}
+
+	#[test]
+	fn truncated_input_is_rejected() {
+		for msg in messages() {
+			let buf = msg.encode().unwrap();
+			for len in 0..buf.len() {
+				assert_eq!(PeerMessage::decode(&buf[..len]), None, "{:?} cut at {}", msg, len);
+			}
+		}
+	}
+
+	#[test]
+	fn unknown_type_and_bad_ip_length_are_rejected() {
+		assert_eq!(PeerMessage::decode(&[99,0,0,0,1,0]), None);
+		assert_eq!(PeerMessage::decode(&[MSG_DNS_ANSWER,0,0,0,1,1,5,1,2,3,4,5,0]), None);
+	}
+
+	#[test]
+	fn long_hostname_is_rejected() {
+		let host = "a".repeat(255);
+		assert!(PeerMessage::DnsQuery { id: 1, host }.encode().is_some());
+		let host = "a".repeat(256);
+		assert_eq!(PeerMessage::DnsQuery { id: 1, host }.encode(), None);
+		// 128 two byte characters
+		let host = "ä".repeat(128);
+		assert_eq!(PeerMessage::DnsQuery { id: 1, host }.encode(), None);
+	}
+}
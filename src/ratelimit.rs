@@ -0,0 +1,230 @@
+// Token bucket rate limits for relayed data and peer messages.
+//
+// Configured in KiB/s in the [RateLimit] section:
+//
+//      [RateLimit]
+//      Global = 10000
+//      Node->3 = 2000
+//      Client->192.168.1.10 = 500
+//
+// The socks handshake supports no authentication, so a client is
+// identified by its IP address. A connection is limited by all buckets,
+// which apply to it. The buckets are shared by all connections.
+//
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::time::{Duration,Instant};
+
+#[derive(Debug,Clone,Default)]
+pub struct RateLimits {
+    pub global: Option<u64>,
+    pub nodes: HashMap<u8,u64>,
+    pub clients: HashMap<IpAddr,u64>
+}
+
+impl RateLimits {
+    pub fn read_entry(&mut self, key: &str, value: &str) -> Result<(),String> {
+        let kib = match u64::from_str(value.trim()) {
+            Ok(kib) if kib > 0 => kib,
+            _ => return Err(format!("rate limit <{}> must be KiB/s > 0", value))
+        };
+        if key == "Global" {
+            self.global = Some(kib);
+        }
+        else if key.starts_with("Node->") {
+            match u8::from_str(&key[6..]) {
+                Ok(id) => { self.nodes.insert(id,kib); },
+                Err(_) => return Err(format!("bad node id in {}", key))
+            }
+        }
+        else if key.starts_with("Client->") {
+            match IpAddr::from_str(&key[8..]) {
+                Ok(ip) => { self.clients.insert(ip,kib); },
+                Err(_) => return Err(format!("bad client address in {}", key))
+            }
+        }
+        else {
+            return Err(format!("unknown key {}", key))
+        }
+        Ok(())
+    }
+}
+
+// Tokens are bytes. The bucket holds at most one second worth of data.
+// Tokens may become negative, if more has been sent than available.
+pub struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant
+}
+
+impl TokenBucket {
+    pub fn new(kib_per_s: u64) -> TokenBucket {
+        let rate = (kib_per_s * 1024) as f64;
+        TokenBucket {
+            rate,
+            tokens: rate,
+            last: Instant::now()
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let dt = now - self.last;
+        let secs = dt.as_secs() as f64 + dt.subsec_nanos() as f64 * 1e-9;
+        self.tokens = (self.tokens + self.rate * secs).min(self.rate);
+        self.last = now;
+    }
+
+    fn available(&mut self) -> usize {
+        self.refill();
+        if self.tokens > 0.0 { self.tokens as usize } else { 0 }
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.tokens -= n as f64;
+    }
+
+    fn wait_for(&mut self, n: usize) -> Duration {
+        self.refill();
+        let missing = n as f64 - self.tokens;
+        if missing <= 0.0 {
+            return Duration::from_secs(0)
+        }
+        let secs = missing / self.rate;
+        Duration::new(secs as u64, ((secs.fract()) * 1e9) as u32)
+    }
+}
+
+type SharedBucket = Rc<RefCell<TokenBucket>>;
+
+// All buckets, which apply to one connection or the peer sender
+#[derive(Clone)]
+pub struct Limiter {
+    buckets: Vec<SharedBucket>
+}
+
+impl Limiter {
+    pub fn available(&self) -> usize {
+        self.buckets.iter()
+            .map(|b| b.borrow_mut().available())
+            .fold(usize::MAX, cmp::min)
+    }
+
+    pub fn consume(&self, n: usize) {
+        for b in &self.buckets {
+            b.borrow_mut().consume(n)
+        }
+    }
+
+    pub fn wait_for(&self, n: usize) -> Duration {
+        self.buckets.iter()
+            .map(|b| b.borrow_mut().wait_for(n))
+            .fold(Duration::from_secs(0), cmp::max)
+    }
+
+    // Takes n tokens and returns the time to wait before sending
+    pub fn reserve(&self, n: usize) -> Duration {
+        let dt = self.wait_for(n);
+        self.consume(n);
+        dt
+    }
+}
+
+pub struct RateLimiters {
+    global: Option<SharedBucket>,
+    nodes: HashMap<u8,SharedBucket>,
+    clients: HashMap<IpAddr,SharedBucket>
+}
+
+impl RateLimiters {
+    pub fn new(limits: &RateLimits) -> RateLimiters {
+        let bucket = |kib: u64| Rc::new(RefCell::new(TokenBucket::new(kib)));
+        RateLimiters {
+            global: limits.global.map(&bucket),
+            nodes: limits.nodes.iter().map(|(id,kib)| (*id,bucket(*kib))).collect(),
+            clients: limits.clients.iter().map(|(ip,kib)| (*ip,bucket(*kib))).collect()
+        }
+    }
+
+    pub fn limiter(&self, client: Option<IpAddr>, node: Option<u8>) -> Option<Limiter> {
+        let mut buckets: Vec<SharedBucket> = vec!();
+        if let Some(ref b) = self.global {
+            buckets.push(b.clone());
+        }
+        if let Some(b) = node.and_then(|id| self.nodes.get(&id)) {
+            buckets.push(b.clone());
+        }
+        if let Some(b) = client.and_then(|ip| self.clients.get(&ip)) {
+            buckets.push(b.clone());
+        }
+        if buckets.len() > 0 { Some(Limiter { buckets }) } else { None }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_starts_full_and_goes_negative() {
+        let mut bucket = TokenBucket::new(1);
+        assert_eq!(bucket.available(), 1024);
+        assert_eq!(bucket.wait_for(1024), Duration::from_secs(0));
+        bucket.consume(1024 + 512);
+        assert_eq!(bucket.available(), 0);
+        // 512 bytes missing plus 1024 wanted at 1 KiB/s
+        let dt = bucket.wait_for(1024);
+        assert!(dt > Duration::from_millis(1400) && dt <= Duration::from_millis(1500));
+    }
+
+    #[test]
+    fn refill_is_capped_at_one_second() {
+        let mut bucket = TokenBucket::new(1);
+        bucket.last -= Duration::from_secs(10);
+        assert_eq!(bucket.available(), 1024);
+        bucket.consume(1024);
+        bucket.last -= Duration::from_millis(500);
+        let available = bucket.available();
+        assert!((512..600).contains(&available));
+    }
+
+    #[test]
+    fn limiter_uses_all_applying_buckets() {
+        let mut limits = RateLimits::default();
+        limits.read_entry("Global", "10").unwrap();
+        limits.read_entry("Node->3", "2").unwrap();
+        limits.read_entry("Client->192.168.1.10", "1").unwrap();
+        let limiters = RateLimiters::new(&limits);
+        let client: IpAddr = "192.168.1.10".parse().unwrap();
+        let other: IpAddr = "192.168.1.11".parse().unwrap();
+
+        assert_eq!(limiters.limiter(Some(client), Some(3)).unwrap().available(), 1024);
+        assert_eq!(limiters.limiter(Some(other), Some(3)).unwrap().available(), 2048);
+        assert_eq!(limiters.limiter(Some(other), None).unwrap().available(), 10240);
+
+        // The buckets are shared
+        limiters.limiter(Some(other), Some(3)).unwrap().consume(1024);
+        let node = limiters.limiter(None, Some(3)).unwrap().available();
+        assert!((1024..1100).contains(&node));
+        let global = limiters.limiter(None, None).unwrap().available();
+        assert!((9216..9300).contains(&global));
+
+        let limits = RateLimits::default();
+        assert!(RateLimiters::new(&limits).limiter(Some(client), Some(3)).is_none());
+    }
+
+    #[test]
+    fn bad_entries() {
+        let mut limits = RateLimits::default();
+        assert!(limits.read_entry("Global", "0").is_err());
+        assert!(limits.read_entry("Global", "fast").is_err());
+        assert!(limits.read_entry("Node->x", "1").is_err());
+        assert!(limits.read_entry("Client->host", "1").is_err());
+        assert!(limits.read_entry("Peer", "1").is_err());
+    }
+}
@@ -0,0 +1,150 @@
+// Named groups of countries.
+//
+// A region is written with a leading @ and can be used instead of a country
+// code in a node's Country key and as a pattern in the [Rules] section:
+//
+//      [Regions]
+//      dach = de,at,ch
+//      nordic = se,no,dk,fi,is
+//      home = @dach,@nordic,nl
+//
+//      [Frankfurt]
+//      Country = @dach:100,@eu:20
+//
+//      [Rules]
+//      50 = @africa -> node 7
+//
+// Built-in regions are the continents and the EU. Regions are expanded into
+// country codes while the config is read.
+//
+use std::collections::HashMap;
+use country::country_hash;
+
+// Nesting depth of regions, which refer to other regions
+const MAX_DEPTH: usize = 8;
+
+const BUILTIN: &[(&str,&str)] = &[
+    ("africa", "dz,ao,bj,bw,bf,bi,cv,cm,cf,td,km,cg,cd,ci,dj,eg,gq,er,sz,et,ga,gm,gh,gn,gw,ke,\
+                ls,lr,ly,mg,mw,ml,mr,mu,yt,ma,mz,na,ne,ng,re,rw,sh,st,sn,sc,sl,so,za,ss,sd,tz,\
+                tg,tn,ug,zm,zw"),
+    ("asia", "af,am,az,bh,bd,bt,bn,kh,cn,cy,ge,hk,in,id,ir,iq,il,jp,jo,kz,kw,kg,la,lb,mo,my,mv,\
+              mn,mm,np,kp,om,pk,ps,ph,qa,sa,sg,kr,lk,sy,tw,tj,th,tl,tr,tm,ae,uz,vn,ye,io"),
+    ("europe", "ad,al,at,ax,ba,be,bg,by,ch,cz,de,dk,ee,es,fi,fo,fr,gb,gg,gi,gr,hr,hu,ie,im,is,it,\
+                je,li,lt,lu,lv,mc,md,me,mk,mt,nl,no,pl,pt,ro,rs,ru,se,si,sj,sk,sm,ua,va,xk"),
+    ("north-america", "ag,ai,aw,bb,bl,bm,bq,bs,bz,ca,cr,cu,cw,dm,do,gd,gl,gp,gt,hn,ht,jm,kn,ky,\
+                       lc,mf,mq,ms,mx,ni,pa,pm,pr,sv,sx,tc,tt,us,vc,vg,vi,um"),
+    ("south-america", "ar,bo,br,cl,co,ec,fk,gf,gy,pe,py,sr,uy,ve,gs"),
+    ("oceania", "as,au,ck,fj,fm,gu,ki,mh,mp,nc,nf,nr,nu,nz,pf,pg,pn,pw,sb,tk,to,tv,vu,wf,ws,cc,cx"),
+    ("antarctica", "aq,bv,tf"),
+    ("eu", "at,be,bg,hr,cy,cz,dk,ee,fi,fr,de,gr,hu,ie,it,lv,lt,lu,mt,nl,pl,pt,ro,sk,si,es,se")
+];
+
+#[derive(Debug)]
+pub struct Regions {
+    // Members are country codes or @regions
+    groups: HashMap<String,Vec<String>>
+}
+
+impl Regions {
+    pub fn new() -> Regions {
+        let mut regions = Regions {
+            groups: HashMap::new()
+        };
+        for &(name,members) in BUILTIN {
+            regions.groups.insert(name.to_string(), members.split(',').map(|m| m.to_string()).collect());
+        }
+        regions
+    }
+
+    // Entry of the [Regions] section. Built-in regions can be redefined.
+    pub fn define(&mut self, name: &str, members: &str) -> Result<(),String> {
+        let name = name.trim().trim_start_matches('@').to_lowercase();
+        if name.is_empty() {
+            return Err("empty region name".to_string())
+        }
+        let members: Vec<String> = members.split(',')
+                                          .map(|m| m.trim().to_lowercase())
+                                          .filter(|m| m.len() > 0)
+                                          .collect();
+        if members.is_empty() {
+            return Err(format!("region {} has no countries", name))
+        }
+        self.groups.insert(name, members);
+        Ok(())
+    }
+
+    // Expands a country code or a @region into country codes
+    pub fn expand(&self, item: &str) -> Result<Vec<usize>,String> {
+        let mut codes: Vec<usize> = vec!();
+        self.expand_into(&item.trim().to_lowercase(), &mut codes, 0)?;
+        Ok(codes)
+    }
+
+    fn expand_into(&self, item: &str, codes: &mut Vec<usize>, depth: usize) -> Result<(),String> {
+        if item.starts_with('@') {
+            if depth >= MAX_DEPTH {
+                return Err(format!("region {} is nested too deep or refers to itself", item))
+            }
+            let members = match self.groups.get(&item[1..]) {
+                Some(members) => members,
+                None => return Err(format!("unknown region {}", item))
+            };
+            for member in members {
+                self.expand_into(member, codes, depth+1)?;
+            }
+            return Ok(())
+        }
+        let cb = item.as_bytes();
+        match if cb.len() == 2 { country_hash(&[cb[0],cb[1]]) } else { None } {
+            Some(code) => {
+                if !codes.contains(&code) {
+                    codes.push(code)
+                }
+                Ok(())
+            },
+            None => Err(format!("<{}> is no two letter country code or @region", item))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn codes(list: &[&[u8;2]]) -> Vec<usize> {
+        list.iter().map(|c| country_hash(c).unwrap()).collect()
+    }
+
+    #[test]
+    fn expand_country_and_builtin() {
+        let regions = Regions::new();
+        assert_eq!(regions.expand("DE").unwrap(), codes(&[b"de"]));
+        let eu = regions.expand("@eu").unwrap();
+        assert_eq!(eu.len(), 27);
+        assert!(eu.contains(&country_hash(b"de").unwrap()));
+        assert!(!eu.contains(&country_hash(b"ch").unwrap()));
+        assert!(regions.expand("@atlantis").is_err());
+        assert!(regions.expand("deu").is_err());
+        assert!(regions.expand("xx").is_err());
+    }
+
+    #[test]
+    fn nested_regions_without_duplicates() {
+        let mut regions = Regions::new();
+        regions.define("dach", "de, at, ch").unwrap();
+        regions.define("@home", "@dach,nl,de").unwrap();
+        assert_eq!(regions.expand("@home").unwrap(), codes(&[b"de", b"at", b"ch", b"nl"]));
+        // redefining a built-in region
+        regions.define("eu", "fr").unwrap();
+        assert_eq!(regions.expand("@eu").unwrap(), codes(&[b"fr"]));
+    }
+
+    #[test]
+    fn bad_definitions() {
+        let mut regions = Regions::new();
+        assert!(regions.define("@", "de").is_err());
+        assert!(regions.define("empty", " , ").is_err());
+        regions.define("loop", "@loop").unwrap();
+        assert!(regions.expand("@loop").is_err());
+    }
+}
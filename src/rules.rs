@@ -0,0 +1,313 @@
+// Routing rules are read from the [Rules] section of the config file.
+// They are evaluated in the order of their numeric keys and before
+// any country based selection takes place. First match wins.
+//
+//      [Rules]
+//      10 = *.netflix.com -> node 3
+//      20 = *.cn -> node 5
+//      30 = 10.0.0.0/8 -> direct
+//      40 = regex:^api\. -> node 2
+//
+// A rule may end with a priority class, see schedule.rs. A @region
+// pattern matches destinations located in one of its countries.
+//
+// As the addresses and countries of a host are known only after it has
+// been resolved, route() tells, if the rules cannot be decided yet.
+//
+use std::fmt;
+use std::net::IpAddr;
+use regex::Regex;
+use schedule::Priority;
+use region::Regions;
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Target {
+    Node(u8),
+    Direct
+}
+
+#[derive(Debug)]
+pub enum Pattern {
+    Domain(String),
+    DomainSuffix(String),
+    Cidr(IpAddr,u8),
+    Regex(Regex),
+    Countries(Vec<usize>)
+}
+
+#[derive(Debug,Clone,Copy)]
+pub enum Route<'a> {
+    Matched(&'a Rule),
+    NoMatch,
+    // A rule before the first match needs the addresses of the host
+    Unknown
+}
+
+#[derive(Debug)]
+pub struct Rule {
+    pub pattern: Pattern,
+    pub target: Target,
+    pub priority: Option<Priority>
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Target::Node(id) => write!(f, "node {}", id),
+            Target::Direct => write!(f, "direct")
+        }
+    }
+}
+
+impl Rule {
+    pub fn parse(line: &str, regions: &Regions) -> Result<Rule,String> {
+        let mut parts = line.splitn(2, "->");
+        let pattern = parts.next().unwrap_or("").trim();
+        let mut target = match parts.next() {
+            Some(t) => t.trim(),
+            None => return Err(format!("missing '->' in rule <{}>", line))
+        };
+        let mut priority = None;
+        if let Some(pos) = target.rfind(char::is_whitespace) {
+            if let Some(p) = Priority::parse(&target[pos+1..]) {
+                priority = Some(p);
+                target = target[..pos].trim();
+            }
+        }
+        Ok(Rule {
+            pattern: Pattern::parse(pattern, regions)?,
+            target: Target::parse(target)?,
+            priority
+        })
+    }
+
+    pub fn matches_host(&self, host: &str) -> bool {
+        match self.pattern {
+            Pattern::Domain(ref d) => host == d,
+            Pattern::DomainSuffix(ref d) => {
+                host == d || (host.ends_with(d.as_str())
+                              && host.as_bytes()[host.len()-d.len()-1] == b'.')
+            },
+            Pattern::Regex(ref re) => re.is_match(host),
+            Pattern::Cidr(..) | Pattern::Countries(..) => false
+        }
+    }
+
+    pub fn matches_ip(&self, ip: &IpAddr) -> bool {
+        match self.pattern {
+            Pattern::Cidr(ref net, prefix) => cidr_contains(net, prefix, ip),
+            _ => false
+        }
+    }
+
+    pub fn matches_countries(&self, codes: &[usize]) -> bool {
+        match self.pattern {
+            Pattern::Countries(ref list) => codes.iter().any(|code| list.contains(code)),
+            _ => false
+        }
+    }
+}
+
+// Checks the rules in order, first match wins. None for ips or codes means
+// not known yet. The matched rule gives the target and the priority.
+pub fn route<'a>(rules: &'a [Rule], host: Option<&str>, ips: Option<&[IpAddr]>,
+                 codes: Option<&[usize]>) -> Route<'a> {
+    for rule in rules {
+        let matched = match rule.pattern {
+            Pattern::Cidr(..) => match ips {
+                Some(ips) => ips.iter().any(|ip| rule.matches_ip(ip)),
+                None => return Route::Unknown
+            },
+            Pattern::Countries(..) => match codes {
+                Some(codes) => rule.matches_countries(codes),
+                None => return Route::Unknown
+            },
+            _ => host.is_some_and(|host| rule.matches_host(host))
+        };
+        if matched {
+            return Route::Matched(rule)
+        }
+    }
+    Route::NoMatch
+}
+
+impl Target {
+    fn parse(s: &str) -> Result<Target,String> {
+        if s == "direct" {
+            return Ok(Target::Direct)
+        }
+        let id = if s.starts_with("node") { s[4..].trim() } else { s };
+        match id.parse::<u8>() {
+            Ok(id) => Ok(Target::Node(id)),
+            Err(_) => Err(format!("unknown rule target <{}>", s))
+        }
+    }
+}
+
+impl Pattern {
+    fn parse(s: &str, regions: &Regions) -> Result<Pattern,String> {
+        if s.is_empty() {
+            return Err("empty rule pattern".to_string())
+        }
+        if s.starts_with('@') {
+            return Ok(Pattern::Countries(regions.expand(s)?))
+        }
+        if s.starts_with("regex:") {
+            return match Regex::new(&s[6..]) {
+                Ok(re) => Ok(Pattern::Regex(re)),
+                Err(e) => Err(format!("bad regex <{}>: {}", &s[6..], e))
+            }
+        }
+        if s.starts_with("*.") {
+            return Ok(Pattern::DomainSuffix(s[2..].to_lowercase()))
+        }
+        let mut net = s.splitn(2, '/');
+        if let Ok(ip) = net.next().unwrap().parse::<IpAddr>() {
+            let max_prefix = if ip.is_ipv4() { 32 } else { 128 };
+            let prefix = match net.next() {
+                None => max_prefix,
+                Some(p) => match p.parse::<u8>() {
+                    Ok(p) if p <= max_prefix => p,
+                    _ => return Err(format!("bad prefix length in <{}>", s))
+                }
+            };
+            return Ok(Pattern::Cidr(ip, prefix))
+        }
+        Ok(Pattern::Domain(s.to_lowercase()))
+    }
+}
+
+fn cidr_contains(net: &IpAddr, prefix: u8, ip: &IpAddr) -> bool {
+    match (net, ip) {
+        (&IpAddr::V4(ref net), &IpAddr::V4(ref ip)) => {
+            if prefix == 0 { return true }
+            let mask = !0u32 << (32 - prefix as u32);
+            (u32::from(*net) & mask) == (u32::from(*ip) & mask)
+        },
+        (&IpAddr::V6(ref net), &IpAddr::V6(ref ip)) => {
+            if prefix == 0 { return true }
+            let mask = !0u128 << (128 - prefix as u32);
+            (u128::from(*net) & mask) == (u128::from(*ip) & mask)
+        },
+        _ => false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Target of the matched rule, NoMatch and Unknown as strings
+    fn target(route: Route) -> Result<Target,&'static str> {
+        match route {
+            Route::Matched(rule) => Ok(rule.target),
+            Route::NoMatch => Err("no match"),
+            Route::Unknown => Err("unknown")
+        }
+    }
+
+    fn rules(lines: &[&str]) -> Vec<Rule> {
+        let regions = Regions::new();
+        lines.iter().map(|line| Rule::parse(line, &regions).unwrap()).collect()
+    }
+
+    #[test]
+    fn parse_patterns_and_targets() {
+        let regions = Regions::new();
+        let rule = Rule::parse("*.Netflix.com -> node 3", &regions).unwrap();
+        assert!(rule.matches_host("www.netflix.com"));
+        assert!(rule.matches_host("netflix.com"));
+        assert!(!rule.matches_host("notnetflix.com"));
+        assert_eq!(rule.target, Target::Node(3));
+        assert!(rule.priority.is_none());
+
+        let rule = Rule::parse("example.org -> 7", &regions).unwrap();
+        assert!(rule.matches_host("example.org"));
+        assert!(!rule.matches_host("www.example.org"));
+        assert_eq!(rule.target, Target::Node(7));
+
+        let rule = Rule::parse("regex:^api\\. -> direct", &regions).unwrap();
+        assert!(rule.matches_host("api.example.org"));
+        assert!(!rule.matches_host("www.api.org"));
+        assert_eq!(rule.target, Target::Direct);
+
+        let rule = Rule::parse("192.168.0.0/16 -> direct", &regions).unwrap();
+        assert!(rule.matches_ip(&"192.168.7.1".parse().unwrap()));
+        assert!(!rule.matches_ip(&"192.169.0.1".parse().unwrap()));
+        assert!(!rule.matches_ip(&"::1".parse().unwrap()));
+
+        let rule = Rule::parse("2001:db8::1 -> node 1", &regions).unwrap();
+        assert!(rule.matches_ip(&"2001:db8::1".parse().unwrap()));
+        assert!(!rule.matches_ip(&"2001:db8::2".parse().unwrap()));
+
+        let rule = Rule::parse("@eu -> node 4", &regions).unwrap();
+        assert!(rule.matches_countries(&regions.expand("fr").unwrap()));
+        assert!(!rule.matches_countries(&regions.expand("ch").unwrap()));
+    }
+
+    #[test]
+    fn parse_priority() {
+        let regions = Regions::new();
+        let rule = Rule::parse("*.example.org -> node 2 bulk:4", &regions).unwrap();
+        assert_eq!(rule.target, Target::Node(2));
+        assert_eq!(rule.priority, Some(Priority::Bulk(4)));
+        let rule = Rule::parse("10.0.0.0/8 -> direct interactive", &regions).unwrap();
+        assert_eq!(rule.target, Target::Direct);
+        assert_eq!(rule.priority, Some(Priority::Interactive));
+        let rule = Rule::parse("*.example.org -> node 2", &regions).unwrap();
+        assert!(rule.priority.is_none());
+    }
+
+    #[test]
+    fn parse_errors() {
+        let regions = Regions::new();
+        assert!(Rule::parse("*.example.org", &regions).is_err());
+        assert!(Rule::parse(" -> node 1", &regions).is_err());
+        assert!(Rule::parse("example.org -> node x", &regions).is_err());
+        assert!(Rule::parse("example.org -> node 256", &regions).is_err());
+        assert!(Rule::parse("10.0.0.0/33 -> direct", &regions).is_err());
+        assert!(Rule::parse("regex:( -> direct", &regions).is_err());
+        assert!(Rule::parse("@atlantis -> direct", &regions).is_err());
+    }
+
+    #[test]
+    fn earlier_cidr_rule_beats_later_host_rule() {
+        let rules = rules(&["10.0.0.0/8 -> direct", "*.corp.de -> node 3"]);
+        let host = Some("intranet.corp.de");
+        assert_eq!(target(route(&rules, host, None, None)), Err("unknown"));
+        let ips: Vec<IpAddr> = vec!("10.1.2.3".parse().unwrap());
+        assert_eq!(target(route(&rules, host, Some(&ips), Some(&[]))), Ok(Target::Direct));
+        let ips: Vec<IpAddr> = vec!("1.2.3.4".parse().unwrap());
+        assert_eq!(target(route(&rules, host, Some(&ips), Some(&[]))), Ok(Target::Node(3)));
+    }
+
+    #[test]
+    fn host_rule_decides_without_addresses() {
+        let rules = rules(&["*.corp.de -> node 3", "10.0.0.0/8 -> direct"]);
+        assert_eq!(target(route(&rules, Some("a.corp.de"), None, None)), Ok(Target::Node(3)));
+        assert_eq!(target(route(&rules, Some("example.com"), None, None)), Err("unknown"));
+        assert_eq!(target(route(&rules, None, Some(&[]), Some(&[]))), Err("no match"));
+    }
+
+    #[test]
+    fn country_rules_need_codes() {
+        let rules = rules(&["@europe -> node 2"]);
+        let de = Regions::new().expand("de").unwrap();
+        assert_eq!(target(route(&rules, Some("example.de"), None, None)), Err("unknown"));
+        assert_eq!(target(route(&rules, Some("example.de"), None, Some(&de))), Ok(Target::Node(2)));
+    }
+
+    #[test]
+    fn priority_comes_from_the_matched_rule() {
+        let rules = rules(&["*.example.com -> node 2", "*.com -> node 3 bulk:4",
+                            "@europe -> node 4 interactive"]);
+        let priority = |host: &str, codes: &[usize]| match route(&rules, Some(host), Some(&[]), Some(codes)) {
+            Route::Matched(rule) => rule.priority,
+            _ => panic!("no rule matched")
+        };
+        let de = Regions::new().expand("de").unwrap();
+        assert_eq!(priority("www.example.com", &[]), None);
+        assert_eq!(priority("www.example2.com", &[]), Some(Priority::Bulk(4)));
+        assert_eq!(priority("www.example.de", &de), Some(Priority::Interactive));
+    }
+}
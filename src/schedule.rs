@@ -0,0 +1,281 @@
+// Priority classes for relayed streams and peer messages.
+//
+// Bulk streams share the bandwidth by weighted fair queuing: Per poll a bulk
+// stream reads at most QUANTUM times its weight and then yields to the other
+// tasks of the reactor. Interactive streams (ssh, rdp, dns,...) are
+// preferred: As long as an interactive stream holds data, which could not be
+// written yet, bulk streams read only 1/HELD_SHARE of that per poll. They
+// never stop completely, so a stalled interactive client cannot starve them.
+//
+// The class is assigned by destination port in the [Priority] section or
+// by the first matching rule, which names a class:
+//
+//      [Priority]
+//      Interactive = 22,23,53,3389
+//
+//      [Rules]
+//      10 = *.ssh.example.com -> node 3 interactive
+//      20 = *.cdn.example.com -> node 3 bulk:4
+//
+// Messages to peers are sent from two queues. Interactive messages (dns
+// queries and answers, close) are always sent before bulk messages.
+//
+use std::cell::Cell;
+use std::cmp;
+use std::fmt;
+use std::rc::Rc;
+use std::str::FromStr;
+use futures::{Async, Poll, Stream};
+
+// Bytes a bulk stream with weight 1 may read per poll
+const QUANTUM: usize = 16 * 1024;
+
+// Part of the quantum, which bulk streams get while interactive data waits
+const HELD_SHARE: usize = 8;
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Priority {
+    Interactive,
+    // Weight for fair queuing, at least 1
+    Bulk(u8)
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Priority::Interactive => write!(f, "interactive"),
+            Priority::Bulk(weight) => write!(f, "bulk:{}", weight)
+        }
+    }
+}
+
+impl Priority {
+    // interactive, bulk or bulk:weight
+    pub fn parse(s: &str) -> Option<Priority> {
+        if s == "interactive" {
+            return Some(Priority::Interactive)
+        }
+        if s == "bulk" {
+            return Some(Priority::Bulk(1))
+        }
+        if s.starts_with("bulk:") {
+            return match u8::from_str(&s[5..]) {
+                Ok(w) if w > 0 => Some(Priority::Bulk(w)),
+                _ => None
+            }
+        }
+        None
+    }
+}
+
+#[derive(Debug,Clone,Default)]
+pub struct PriorityConfig {
+    pub interactive_ports: Vec<u16>
+}
+
+impl PriorityConfig {
+    pub fn read_entry(&mut self, key: &str, value: &str) -> Result<(),String> {
+        match key {
+            "Interactive" => {
+                for port in value.split(',') {
+                    match u16::from_str(port.trim()) {
+                        Ok(port) => self.interactive_ports.push(port),
+                        Err(_) => return Err(format!("bad port <{}> in {}", port, key))
+                    }
+                }
+                Ok(())
+            },
+            _ => Err(format!("unknown key {}", key))
+        }
+    }
+}
+
+// Shared by all relayed streams
+#[derive(Default)]
+pub struct Scheduler {
+    // Interactive streams with unwritten data
+    holding: Cell<usize>
+}
+
+impl Scheduler {
+    fn hold(&self) {
+        self.holding.set(self.holding.get() + 1);
+    }
+
+    fn release(&self) {
+        self.holding.set(self.holding.get() - 1);
+    }
+}
+
+// Scheduling state of one direction of a relayed stream
+pub struct Class {
+    scheduler: Rc<Scheduler>,
+    priority: Priority,
+    holding: bool,
+    budget: usize
+}
+
+impl Class {
+    pub fn new(scheduler: Rc<Scheduler>, priority: Priority) -> Class {
+        Class {
+            scheduler,
+            priority,
+            holding: false,
+            budget: 0
+        }
+    }
+
+    // Called at the start of each poll
+    pub fn start_round(&mut self) {
+        if let Priority::Bulk(weight) = self.priority {
+            self.budget = QUANTUM * weight as usize;
+            if self.scheduler.holding.get() > 0 {
+                self.budget /= HELD_SHARE;
+            }
+        }
+    }
+
+    // Bytes, which may be read now. Zero for bulk streams means, that
+    // the task has to yield after this round.
+    pub fn allowance(&mut self, room: usize) -> usize {
+        match self.priority {
+            Priority::Interactive => room,
+            Priority::Bulk(_) => cmp::min(room, self.budget)
+        }
+    }
+
+    pub fn consume(&mut self, n: usize) {
+        self.budget = self.budget.saturating_sub(n);
+    }
+
+    // A bulk stream with exhausted budget continues in the next round
+    pub fn exhausted(&self) -> bool {
+        match self.priority {
+            Priority::Interactive => false,
+            Priority::Bulk(_) => self.budget == 0
+        }
+    }
+
+    // Interactive streams report, if they have unwritten data
+    pub fn set_holding(&mut self, holding: bool) {
+        if self.priority != Priority::Interactive || self.holding == holding {
+            return
+        }
+        self.holding = holding;
+        if holding {
+            self.scheduler.hold()
+        }
+        else {
+            self.scheduler.release()
+        }
+    }
+}
+
+impl Drop for Class {
+    fn drop(&mut self) {
+        self.set_holding(false)
+    }
+}
+
+// Merges the interactive and the bulk queue. Items of high are always
+// taken first. Ends, when both have ended.
+pub struct PriorityStream<H,L> {
+    high: Option<H>,
+    low: Option<L>
+}
+
+impl<H,L> PriorityStream<H,L> {
+    pub fn new(high: H, low: L) -> PriorityStream<H,L> {
+        PriorityStream {
+            high: Some(high),
+            low: Some(low)
+        }
+    }
+}
+
+impl<H,L> Stream for PriorityStream<H,L>
+    where H: Stream, L: Stream<Item=H::Item, Error=H::Error>
+{
+    type Item = H::Item;
+    type Error = H::Error;
+
+    fn poll(&mut self) -> Poll<Option<H::Item>, H::Error> {
+        let high_done = match self.high {
+            Some(ref mut high) => match try!(high.poll()) {
+                Async::Ready(Some(item)) => return Ok(Async::Ready(Some(item))),
+                Async::Ready(None) => true,
+                Async::NotReady => false
+            },
+            None => true
+        };
+        if high_done {
+            self.high = None;
+        }
+        let low_done = match self.low {
+            Some(ref mut low) => match try!(low.poll()) {
+                Async::Ready(Some(item)) => return Ok(Async::Ready(Some(item))),
+                Async::Ready(None) => true,
+                Async::NotReady => false
+            },
+            None => true
+        };
+        if low_done {
+            self.low = None;
+        }
+        if self.high.is_none() && self.low.is_none() {
+            return Ok(Async::Ready(None))
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::Future;
+    use futures::sync::mpsc;
+
+    #[test]
+    fn parse_classes() {
+        assert_eq!(Priority::parse("interactive"), Some(Priority::Interactive));
+        assert_eq!(Priority::parse("bulk"), Some(Priority::Bulk(1)));
+        assert_eq!(Priority::parse("bulk:4"), Some(Priority::Bulk(4)));
+        assert_eq!(Priority::parse("bulk:0"), None);
+        assert_eq!(Priority::parse("fast"), None);
+    }
+
+    #[test]
+    fn bulk_is_slowed_but_not_stopped_by_held_interactive_data() {
+        let scheduler = Rc::new(Scheduler::default());
+        let mut ssh = Class::new(scheduler.clone(), Priority::Interactive);
+        let mut download = Class::new(scheduler.clone(), Priority::Bulk(2));
+
+        download.start_round();
+        assert_eq!(download.allowance(1 << 20), 2 * QUANTUM);
+
+        ssh.set_holding(true);
+        download.start_round();
+        assert_eq!(download.allowance(1 << 20), 2 * QUANTUM / HELD_SHARE);
+        download.consume(2 * QUANTUM / HELD_SHARE);
+        assert!(download.exhausted());
+        assert_eq!(ssh.allowance(100), 100);
+
+        drop(ssh);
+        download.start_round();
+        assert_eq!(download.allowance(1 << 20), 2 * QUANTUM);
+    }
+
+    #[test]
+    fn interactive_messages_overtake_bulk_messages() {
+        let (mut high_tx, high_rx) = mpsc::channel::<u32>(10);
+        let (mut low_tx, low_rx) = mpsc::channel::<u32>(10);
+        low_tx.try_send(10).unwrap();
+        low_tx.try_send(11).unwrap();
+        high_tx.try_send(1).unwrap();
+        high_tx.try_send(2).unwrap();
+        drop(high_tx);
+        drop(low_tx);
+        let sent = PriorityStream::new(high_rx, low_rx).collect().wait().unwrap();
+        assert_eq!(sent, vec!(1, 2, 10, 11));
+    }
+}
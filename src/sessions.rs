@@ -0,0 +1,188 @@
+// Registry of the active socks sessions.
+//
+// A session is registered, when the client's connection is accepted, and
+// updated as it proceeds. Each entry holds the sending half of a kill
+// switch, which is polled by the session's future. Aborting a session
+// closes both sockets, so the application notices at once and can reconnect
+// through another exit.
+//
+// When the health checker finds all proxy chains of a node down, the node
+// is considered dead and all sessions via that node are aborted. The
+// registry is shared with the TUI thread.
+//
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc,Mutex};
+use std::time::Instant;
+use futures::{Async, Future, Poll};
+use futures::sync::oneshot;
+use accounting::Exit;
+use transfer::Traffic;
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum SessionState {
+    Handshake,
+    Resolving,
+    Connecting,
+    Relaying
+}
+
+impl fmt::Display for SessionState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SessionState::Handshake => write!(f, "handshake"),
+            SessionState::Resolving => write!(f, "resolving"),
+            SessionState::Connecting => write!(f, "connecting"),
+            SessionState::Relaying => write!(f, "relaying")
+        }
+    }
+}
+
+#[derive(Debug,Clone)]
+pub struct SessionInfo {
+    pub id: u64,
+    pub client: Option<SocketAddr>,
+    pub target: String,
+    pub exit: Exit,
+    pub country: Option<usize>,
+    pub state: SessionState,
+    pub opened: Instant,
+    pub bytes_up: u64,
+    pub bytes_down: u64
+}
+
+struct Entry {
+    info: SessionInfo,
+    traffic: Traffic,
+    kill: oneshot::Sender<()>
+}
+
+#[derive(Default)]
+struct Registry {
+    next_id: u64,
+    entries: HashMap<u64,Entry>
+}
+
+#[derive(Clone,Default)]
+pub struct Sessions {
+    registry: Arc<Mutex<Registry>>
+}
+
+// Held by the session's future. Unregisters on drop.
+pub struct SessionHandle {
+    id: u64,
+    sessions: Sessions,
+    killed: oneshot::Receiver<()>
+}
+
+impl SessionHandle {
+    fn update<F: FnOnce(&mut SessionInfo)>(&self, f: F) {
+        if let Some(entry) = self.sessions.registry.lock().unwrap().entries.get_mut(&self.id) {
+            f(&mut entry.info)
+        }
+    }
+
+    pub fn set_state(&self, state: SessionState) {
+        self.update(|info| info.state = state)
+    }
+
+    pub fn set_target(&self, target: String) {
+        self.update(|info| info.target = target)
+    }
+
+    pub fn set_exit(&self, exit: Exit) {
+        self.update(|info| info.exit = exit)
+    }
+
+    pub fn set_country(&self, country: Option<usize>) {
+        self.update(|info| info.country = country)
+    }
+
+    // Fails, once the session has been aborted
+    pub fn poll_killed(&mut self) -> Poll<(),io::Error> {
+        match self.killed.poll() {
+            Ok(Async::Ready(())) => Err(io::Error::new(io::ErrorKind::ConnectionAborted, "session aborted")),
+            _ => Ok(Async::NotReady)
+        }
+    }
+}
+
+impl Drop for SessionHandle {
+    fn drop(&mut self) {
+        self.sessions.registry.lock().unwrap().entries.remove(&self.id);
+    }
+}
+
+impl Sessions {
+    pub fn new() -> Sessions {
+        Sessions::default()
+    }
+
+    pub fn register(&self, client: Option<SocketAddr>, target: String, traffic: &Traffic) -> SessionHandle {
+        let (kill,killed) = oneshot::channel();
+        let mut registry = self.registry.lock().unwrap();
+        registry.next_id += 1;
+        let id = registry.next_id;
+        let info = SessionInfo {
+            id,
+            client,
+            target,
+            exit: Exit::Unknown,
+            country: None,
+            state: SessionState::Handshake,
+            opened: Instant::now(),
+            bytes_up: 0,
+            bytes_down: 0
+        };
+        registry.entries.insert(id, Entry { info, traffic: traffic.clone(), kill });
+        SessionHandle {
+            id,
+            sessions: self.clone(),
+            killed
+        }
+    }
+
+    pub fn list(&self) -> Vec<SessionInfo> {
+        let registry = self.registry.lock().unwrap();
+        let mut list: Vec<SessionInfo> = registry.entries.values().map(|e| {
+            let mut info = e.info.clone();
+            info.bytes_up = e.traffic.up();
+            info.bytes_down = e.traffic.down();
+            info
+        }).collect();
+        list.sort_by_key(|info| info.id);
+        list
+    }
+
+    pub fn count(&self) -> usize {
+        self.registry.lock().unwrap().entries.len()
+    }
+
+    pub fn abort(&self, id: u64) -> bool {
+        match self.registry.lock().unwrap().entries.remove(&id) {
+            Some(entry) => {
+                info!("Abort session {} to {}", id, entry.info.target);
+                let _ = entry.kill.send(());
+                true
+            },
+            None => false
+        }
+    }
+
+    // Returns the number of aborted sessions
+    pub fn abort_node(&self, node: u8) -> usize {
+        let mut registry = self.registry.lock().unwrap();
+        let ids: Vec<u64> = registry.entries.values()
+            .filter(|e| match e.info.exit { Exit::Node(id,_) => id == node, _ => false })
+            .map(|e| e.info.id)
+            .collect();
+        for id in &ids {
+            if let Some(entry) = registry.entries.remove(id) {
+                let _ = entry.kill.send(());
+            }
+        }
+        ids.len()
+    }
+}
@@ -0,0 +1,163 @@
+// Zero-copy relay of one direction of a proxy connection on linux.
+//
+// Data is moved with splice(2) from the reading socket into a pipe and from
+// the pipe into the writing socket, so it never enters user space.
+//
+// The sockets are registered with the reactor by their TcpStream. In order
+// to learn about readiness, the file descriptors are duplicated and the
+// duplicates are registered on their own.
+//
+use std::io;
+use std::net::Shutdown;
+use std::os::unix::io::{AsRawFd,RawFd};
+use std::ptr;
+use std::rc::Rc;
+use std::sync::atomic::Ordering;
+
+use libc;
+use futures::{Async, Poll};
+use mio::{self, Evented, PollOpt, Ready, Token};
+use mio::unix::EventedFd;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{Handle, PollEvented};
+use transfer::Counter;
+
+// Default capacity of a linux pipe
+const PIPE_SIZE: usize = 64 * 1024;
+
+// Owned file descriptor, which is closed on drop
+struct Fd(RawFd);
+
+impl Fd {
+    fn dup(fd: RawFd) -> io::Result<Fd> {
+        let res = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
+        if res < 0 {
+            return Err(io::Error::last_os_error())
+        }
+        Ok(Fd(res))
+    }
+}
+
+impl Drop for Fd {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0); }
+    }
+}
+
+impl Evented for Fd {
+    fn register(&self, poll: &mio::Poll, token: Token, interest: Ready, opts: PollOpt) -> io::Result<()> {
+        EventedFd(&self.0).register(poll, token, interest, opts)
+    }
+
+    fn reregister(&self, poll: &mio::Poll, token: Token, interest: Ready, opts: PollOpt) -> io::Result<()> {
+        EventedFd(&self.0).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &mio::Poll) -> io::Result<()> {
+        EventedFd(&self.0).deregister(poll)
+    }
+}
+
+fn splice(fd_in: RawFd, fd_out: RawFd, len: usize) -> io::Result<usize> {
+    let res = unsafe {
+        libc::splice(fd_in, ptr::null_mut(), fd_out, ptr::null_mut(), len,
+                     libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK)
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error())
+    }
+    Ok(res as usize)
+}
+
+pub struct Splice {
+    // Keep the sockets alive, writer is used for shutdown
+    _reader: Rc<TcpStream>,
+    writer: Rc<TcpStream>,
+
+    // Duplicates of the sockets' file descriptors for readiness
+    rd: PollEvented<Fd>,
+    wr: PollEvented<Fd>,
+
+    pipe_r: Fd,
+    pipe_w: Fd,
+
+    // Bytes waiting in the pipe
+    in_pipe: usize,
+    read_done: bool,
+    amt: u64,
+    counter: Counter
+}
+
+impl Splice {
+    pub fn new(reader: Rc<TcpStream>, writer: Rc<TcpStream>, handle: &Handle,
+               counter: Counter) -> io::Result<Splice> {
+        let mut fds: [RawFd; 2] = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error())
+        }
+        let pipe_r = Fd(fds[0]);
+        let pipe_w = Fd(fds[1]);
+        let rd = try!(PollEvented::new(try!(Fd::dup(reader.as_raw_fd())), handle));
+        let wr = try!(PollEvented::new(try!(Fd::dup(writer.as_raw_fd())), handle));
+        Ok(Splice {
+            _reader: reader,
+            writer,
+            rd,
+            wr,
+            pipe_r,
+            pipe_w,
+            in_pipe: 0,
+            read_done: false,
+            amt: 0,
+            counter
+        })
+    }
+
+    // Same semantics as the buffered Transfer::poll
+    pub fn poll(&mut self) -> Poll<u64, io::Error> {
+        loop {
+            let mut progress = false;
+
+            if !self.read_done && self.in_pipe < PIPE_SIZE && self.rd.poll_read().is_ready() {
+                match splice(self.rd.get_ref().0, self.pipe_w.0, PIPE_SIZE - self.in_pipe) {
+                    Ok(0) => {
+                        self.read_done = true;
+                        progress = true;
+                    },
+                    Ok(n) => {
+                        self.in_pipe += n;
+                        progress = true;
+                    },
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => self.rd.need_read(),
+                    Err(e) => return Err(e)
+                }
+            }
+
+            if self.in_pipe > 0 && self.wr.poll_write().is_ready() {
+                match splice(self.pipe_r.0, self.wr.get_ref().0, self.in_pipe) {
+                    Ok(0) => {
+                        return Err(io::Error::new(io::ErrorKind::WriteZero,
+                                                  "write zero byte into writer"))
+                    },
+                    Ok(m) => {
+                        self.in_pipe -= m;
+                        self.amt += m as u64;
+                        self.counter.fetch_add(m as u64, Ordering::Relaxed);
+                        progress = true;
+                    },
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => self.wr.need_write(),
+                    Err(e) => return Err(e)
+                }
+            }
+
+            if self.read_done && self.in_pipe == 0 {
+                try!(self.writer.shutdown(Shutdown::Write));
+                return Ok(self.amt.into())
+            }
+
+            if !progress {
+                return Ok(Async::NotReady)
+            }
+        }
+    }
+}
@@ -0,0 +1,167 @@
+// Derives the country of a destination from its hostname.
+//
+// Only the public suffix is considered, so bbc.co.uk and example.de yield
+// a country, while example.com has to be resolved and located by IP.
+// Some ccTLDs are marketed as generic domains (.io, .tv, ...). Those are
+// listed as vanity TLDs and fall back to IP geolocation, too.
+//
+// The built-in defaults can be changed in the [Tld] section. An explicit
+// suffix entry takes precedence over the Vanity list:
+//
+//      [Tld]
+//      Vanity = io,tv,co,me,fm
+//      berlin = de
+//      gov.uk = geo
+//
+use std::collections::{HashMap,HashSet};
+use country::country_hash;
+
+const DEFAULT_VANITY: &[&str] = &["ai","am","cc","co","fm","gg","io","ly",
+                                  "me","nu","sh","tk","to","tv","ws"];
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum TldClass {
+    Country(usize),
+    Geo
+}
+
+#[derive(Debug)]
+pub struct TldClassifier {
+    // Suffix entries of the [Tld] section
+    suffixes: HashMap<String,TldClass>,
+    vanity: HashSet<String>
+}
+
+impl TldClassifier {
+    pub fn new() -> TldClassifier {
+        TldClassifier {
+            suffixes: HashMap::new(),
+            vanity: DEFAULT_VANITY.iter().map(|s| s.to_string()).collect()
+        }
+    }
+
+    pub fn set_vanity(&mut self, vanity: Vec<String>) {
+        self.vanity = vanity.into_iter().collect();
+    }
+
+    pub fn read_entry(&mut self, key: &str, value: &str) -> Result<(),String> {
+        let key = key.trim().trim_matches('.').to_lowercase();
+        let value = value.trim().to_lowercase();
+        if key == "vanity" {
+            let vanity = value.split(",")
+                              .map(|s| s.trim().trim_matches('.').to_string())
+                              .filter(|s| s.len() > 0)
+                              .collect();
+            self.set_vanity(vanity);
+            return Ok(())
+        }
+        let class = if value == "geo" {
+            TldClass::Geo
+        }
+        else {
+            let cb = value.as_bytes();
+            match if cb.len() == 2 { country_hash(&[cb[0],cb[1]]) } else { None } {
+                Some(code) => TldClass::Country(code),
+                None => return Err(format!("unknown country <{}> for suffix {}", value, key))
+            }
+        };
+        self.suffixes.insert(key, class);
+        Ok(())
+    }
+
+    // Returns the country code, if the hostname determines the country.
+    // None means the destination needs to be resolved and located by IP.
+    pub fn country_of(&self, host: &str) -> Option<usize> {
+        let host = host.trim_end_matches('.');
+        if !host.contains('.') {
+            return None
+        }
+        // Longest configured suffix wins
+        let mut pos = 0;
+        loop {
+            let suffix = &host[pos..];
+            if let Some(class) = self.suffixes.get(suffix) {
+                return match *class {
+                    TldClass::Country(code) => Some(code),
+                    TldClass::Geo => None
+                }
+            }
+            if self.vanity.contains(suffix) {
+                return None
+            }
+            match suffix.find('.') {
+                Some(dot) => pos += dot + 1,
+                None => break
+            }
+        }
+        // .uk is in use instead of the iso code gb
+        let tld = match &host[pos..] {
+            "uk" => "gb",
+            tld => tld
+        };
+        let tb = tld.as_bytes();
+        if tb.len() == 2 {
+            country_hash(&[tb[0],tb[1]])
+        }
+        else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn code(c: &[u8;2]) -> Option<usize> {
+        country_hash(c)
+    }
+
+    #[test]
+    fn country_by_suffix() {
+        let tld = TldClassifier::new();
+        assert_eq!(tld.country_of("example.de"), code(b"de"));
+        assert_eq!(tld.country_of("www.bbc.co.uk"), code(b"gb"));
+        assert_eq!(tld.country_of("example.fr."), code(b"fr"));
+        assert_eq!(tld.country_of("example.com"), None);
+        assert_eq!(tld.country_of("localhost"), None);
+    }
+
+    #[test]
+    fn vanity_tlds_are_located_by_ip() {
+        let mut tld = TldClassifier::new();
+        assert_eq!(tld.country_of("github.io"), None);
+        assert_eq!(tld.country_of("twitch.tv"), None);
+        tld.read_entry("Vanity", "tv").unwrap();
+        assert_eq!(tld.country_of("github.io"), code(b"io"));
+        assert_eq!(tld.country_of("twitch.tv"), None);
+    }
+
+    #[test]
+    fn configured_suffixes() {
+        let mut tld = TldClassifier::new();
+        tld.read_entry("berlin", "de").unwrap();
+        tld.read_entry(".gov.uk", "geo").unwrap();
+        assert_eq!(tld.country_of("example.berlin"), code(b"de"));
+        assert_eq!(tld.country_of("www.gov.uk"), None);
+        assert_eq!(tld.country_of("www.nhs.uk"), code(b"gb"));
+        assert!(tld.read_entry("berlin", "germany").is_err());
+        assert!(tld.read_entry("berlin", "xx").is_err());
+    }
+
+    #[test]
+    fn explicit_suffix_beats_vanity_in_any_order() {
+        let entries = [("gov.uk", "geo"), ("Vanity", "io,me"), ("io", "gb")];
+        let orders: [[usize;3];2] = [[0,1,2], [2,1,0]];
+        for order in orders.iter() {
+            let mut tld = TldClassifier::new();
+            for &i in order.iter() {
+                tld.read_entry(entries[i].0, entries[i].1).unwrap();
+            }
+            assert_eq!(tld.country_of("www.gov.uk"), None);
+            assert_eq!(tld.country_of("www.bbc.co.uk"), code(b"gb"));
+            assert_eq!(tld.country_of("github.io"), code(b"gb"));
+            assert_eq!(tld.country_of("twitch.tv"), code(b"tv"));
+        }
+    }
+}
@@ -1,37 +1,286 @@
-
+use std::cmp;
 use std::io::{self, Read, Write};
 use std::net::Shutdown;
 use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
 
 use futures::{Future, Poll, Async};
+use futures::task;
 use tokio_core::net::TcpStream;
+use tokio_core::reactor::{Handle, Timeout};
+
+use ratelimit::Limiter;
+use schedule::{Class, Priority, Scheduler};
+
+#[cfg(all(target_os = "linux", feature = "splice"))]
+use splice::Splice;
+
+// Size of the buffer of one direction of a proxy connection
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Bytes relayed so far by both directions of a proxy connection.
+///
+/// The counters are shared with the `Transfer` futures, so they are
+/// available even if the connection fails. They are atomic, because the
+/// session registry is read by the TUI thread.
+#[derive(Clone,Default)]
+pub struct Traffic {
+    up: Counter,
+    down: Counter,
+}
+
+// 64 bit, so the counters do not wrap at 4 GiB on 32 bit targets
+pub type Counter = Arc<AtomicU64>;
+
+impl Traffic {
+    pub fn up(&self) -> u64 {
+        self.up.load(Ordering::Relaxed)
+    }
+
+    pub fn down(&self) -> u64 {
+        self.down.load(Ordering::Relaxed)
+    }
+}
 
 /// A future representing reading all data from one side of a proxy connection
 /// and writing it to another.
 ///
-/// This future, unlike the handshake performed above, is implemented via a
-/// custom implementation of the `Future` trait rather than with combinators.
-/// This is intended to show off how the combinators are not all that can be
-/// done with futures, but rather more custom (or optimized) implementations can
-/// be implemented with just a trait impl!
+/// Each direction owns a buffer with a read and a write cursor. Data, which
+/// could not be written yet, stays in the buffer until the writer is ready
+/// again. New data is only read, if the buffer has room left. So a slow
+/// writer throttles the reader instead of data piling up.
 pub struct Transfer {
     // The two I/O objects we'll be reading.
     reader: Rc<TcpStream>,
     writer: Rc<TcpStream>,
 
+    // Data in buf[pos..cap] has been read, but not written yet
+    buf: Box<[u8]>,
+    pos: usize,
+    cap: usize,
+
+    // Reader has reached EOF
+    read_done: bool,
+
     // The number of bytes we've written so far.
     amt: u64,
+    counter: Counter,
+
+    // Reads are limited to the tokens available. If there are none,
+    // the delay wakes up the task, when tokens are expected again.
+    limiter: Option<Limiter>,
+    delay: Option<Timeout>,
+    handle: Option<Handle>,
+
+    // Priority class, if scheduling is configured
+    class: Option<Class>,
+
+    // Zero-copy path, which replaces the buffer
+    #[cfg(all(target_os = "linux", feature = "splice"))]
+    splice: Option<Splice>,
 }
 
 impl Transfer {
     pub fn new(reader: Rc<TcpStream>,
-           writer: Rc<TcpStream>) -> Transfer {
+           writer: Rc<TcpStream>,
+           counter: Counter) -> Transfer {
         Transfer {
             reader: reader,
             writer: writer,
+            buf: vec![0; BUFFER_SIZE].into_boxed_slice(),
+            pos: 0,
+            cap: 0,
+            read_done: false,
             amt: 0,
+            counter: counter,
+            limiter: None,
+            delay: None,
+            handle: None,
+            class: None,
+            #[cfg(all(target_os = "linux", feature = "splice"))]
+            splice: None,
+        }
+    }
+
+    // Rate limited and scheduled connections always use the buffer
+    fn shaped(reader: Rc<TcpStream>, writer: Rc<TcpStream>, handle: &Handle,
+              counter: Counter, limiter: Option<Limiter>,
+              class: Option<Class>) -> Transfer {
+        let mut transfer = Transfer::new(reader, writer, counter);
+        transfer.limiter = limiter;
+        transfer.class = class;
+        transfer.handle = Some(handle.clone());
+        transfer
+    }
+
+    // Number of bytes, which may be read now. Zero means wait for delay,
+    // for interactive streams or for the next round.
+    fn allowance(&mut self) -> io::Result<usize> {
+        let mut room = self.buf.len() - self.cap;
+        if let Some(ref mut class) = self.class {
+            room = class.allowance(room);
+            if room == 0 {
+                return Ok(0)
+            }
+        }
+        let limiter = match self.limiter {
+            Some(ref limiter) => limiter,
+            None => return Ok(room)
+        };
+        // The buckets are shared, so other connections may have taken the
+        // tokens before the delay has fired. Then wait again.
+        loop {
+            let available = limiter.available();
+            if available > 0 {
+                self.delay = None;
+                return Ok(cmp::min(room, available))
+            }
+            if self.delay.is_none() {
+                let handle = self.handle.as_ref().expect("limited transfer without handle");
+                self.delay = Some(try!(Timeout::new(limiter.wait_for(1), handle)));
+            }
+            if let Some(ref mut delay) = self.delay {
+                if let Async::NotReady = try!(delay.poll()) {
+                    return Ok(0)
+                }
+            }
+            self.delay = None;
         }
     }
+
+    #[cfg(all(target_os = "linux", feature = "splice"))]
+    fn relay(reader: Rc<TcpStream>, writer: Rc<TcpStream>, handle: &Handle,
+             counter: Counter) -> Transfer {
+        match Splice::new(reader.clone(), writer.clone(), handle, counter.clone()) {
+            Ok(splice) => {
+                let mut transfer = Transfer::new(reader, writer, counter);
+                transfer.buf = Vec::new().into_boxed_slice();
+                transfer.splice = Some(splice);
+                transfer
+            },
+            Err(e) => {
+                warn!("splice not available ({}), use buffered copy", e);
+                Transfer::new(reader, writer, counter)
+            }
+        }
+    }
+
+    #[cfg(not(all(target_os = "linux", feature = "splice")))]
+    fn relay(reader: Rc<TcpStream>, writer: Rc<TcpStream>, _handle: &Handle,
+             counter: Counter) -> Transfer {
+        Transfer::new(reader, writer, counter)
+    }
+}
+
+/// Both directions of a proxy connection.
+///
+/// Each direction completes on its own. After the first direction has seen
+/// EOF and passed it on, the other one may continue for the linger time.
+/// If a direction fails, e.g. by a reset, both sockets are reset.
+pub struct Relay {
+    source: Rc<TcpStream>,
+    destination: Rc<TcpStream>,
+    up: Option<Transfer>,
+    down: Option<Transfer>,
+    handle: Handle,
+    linger: Duration,
+    linger_timeout: Option<Timeout>,
+}
+
+impl Relay {
+    /// With the feature `splice` on linux the data is relayed with splice(2),
+    /// otherwise it is copied through the buffers.
+    /// A rate limited or scheduled relay uses the buffers. Both directions
+    /// take their tokens from the same buckets and have the same priority.
+    /// Data from source to destination is counted as up in `traffic`.
+    pub fn new(source: TcpStream, destination: TcpStream, handle: &Handle,
+               traffic: &Traffic, linger: Duration, limiter: Option<Limiter>,
+               priority: Option<(Rc<Scheduler>, Priority)>) -> Relay {
+        let c1 = Rc::new(source);
+        let c2 = Rc::new(destination);
+
+        let (up, down) = if limiter.is_none() && priority.is_none() {
+            (Transfer::relay(c1.clone(), c2.clone(), handle, traffic.up.clone()),
+             Transfer::relay(c2.clone(), c1.clone(), handle, traffic.down.clone()))
+        }
+        else {
+            let class = |p: &Option<(Rc<Scheduler>, Priority)>| {
+                p.as_ref().map(|&(ref s, prio)| Class::new(s.clone(), prio))
+            };
+            (Transfer::shaped(c1.clone(), c2.clone(), handle, traffic.up.clone(),
+                              limiter.clone(), class(&priority)),
+             Transfer::shaped(c2.clone(), c1.clone(), handle, traffic.down.clone(),
+                              limiter, class(&priority)))
+        };
+        Relay {
+            source: c1,
+            destination: c2,
+            up: Some(up),
+            down: Some(down),
+            handle: handle.clone(),
+            linger: linger,
+            linger_timeout: None,
+        }
+    }
+
+    // Closing with linger time zero sends a RST instead of FIN
+    fn reset(&mut self) {
+        let _ = self.source.set_linger(Some(Duration::from_secs(0)));
+        let _ = self.destination.set_linger(Some(Duration::from_secs(0)));
+        self.up = None;
+        self.down = None;
+    }
+
+    fn poll_direction(transfer: &mut Option<Transfer>) -> Poll<(), io::Error> {
+        let done = match *transfer {
+            Some(ref mut t) => {
+                try_ready!(t.poll());
+                true
+            },
+            None => true
+        };
+        if done {
+            *transfer = None;
+        }
+        Ok(Async::Ready(()))
+    }
+}
+
+impl Future for Relay {
+    // The relayed bytes are counted in `Traffic`
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        let up = Relay::poll_direction(&mut self.up);
+        let down = Relay::poll_direction(&mut self.down);
+        match (up, down) {
+            (Err(e), _) | (_, Err(e)) => {
+                debug!("Reset both sides after {}", e);
+                self.reset();
+                return Err(e)
+            },
+            _ => ()
+        }
+
+        if self.up.is_none() && self.down.is_none() {
+            return Ok(Async::Ready(()))
+        }
+        if self.up.is_none() || self.down.is_none() {
+            if self.linger_timeout.is_none() {
+                trace!("Half closed, linger for {:?}", self.linger);
+                self.linger_timeout = Some(try!(Timeout::new(self.linger, &self.handle)));
+            }
+            if let Some(ref mut timeout) = self.linger_timeout {
+                try_ready!(timeout.poll());
+            }
+            self.reset();
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "linger timeout after half close"))
+        }
+        Ok(Async::NotReady)
+    }
 }
 
 // Here we implement the `Future` trait for `Transfer` directly. This does not
@@ -57,61 +306,143 @@ impl Future for Transfer {
     /// bytes were transferred), so we don't need to maintain state beyond that
     /// point.
     fn poll(&mut self) -> Poll<u64, io::Error> {
-        let mut buffer = vec![0; 64 * 1024];
+        #[cfg(all(target_os = "linux", feature = "splice"))]
+        {
+            if let Some(ref mut splice) = self.splice {
+                return splice.poll()
+            }
+        }
 
-        // Here we loop over the two TCP halves, reading all data from one
-        // connection and writing it to another. The crucial performance aspect
-        // of this server, however, is that we wait until both the read half and
-        // the write half are ready on the connection, allowing the buffer to
-        // only be temporarily used in a small window for all connections.
+        // Read and write alternately as long as one of both makes progress.
+        // A `WouldBlock` of tokio's TcpStream registers the task for wakeup,
+        // so returning `NotReady` is fine once neither side can continue.
+        if let Some(ref mut class) = self.class {
+            class.start_round();
+        }
         loop {
-            let read_ready = self.reader.poll_read().is_ready();
-            let write_ready = self.writer.poll_write().is_ready();
-            if !read_ready || !write_ready {
-                return Ok(Async::NotReady)
+            let mut progress = false;
+
+            if !self.read_done && self.cap < self.buf.len() {
+                let allowed = try!(self.allowance());
+                if allowed > 0 {
+                    let end = self.cap + allowed;
+                    match (&*self.reader).read(&mut self.buf[self.cap..end]) {
+                        Ok(0) => {
+                            self.read_done = true;
+                            progress = true;
+                        },
+                        Ok(n) => {
+                            if let Some(ref limiter) = self.limiter {
+                                limiter.consume(n);
+                            }
+                            if let Some(ref mut class) = self.class {
+                                class.consume(n);
+                            }
+                            self.cap += n;
+                            progress = true;
+                        },
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                        Err(e) => return Err(e)
+                    }
+                }
+            }
+
+            if self.pos < self.cap {
+                match (&*self.writer).write(&self.buf[self.pos..self.cap]) {
+                    Ok(0) => {
+                        return Err(io::Error::new(io::ErrorKind::WriteZero,
+                                                  "write zero byte into writer"))
+                    },
+                    Ok(m) => {
+                        self.pos += m;
+                        self.amt += m as u64;
+                        self.counter.fetch_add(m as u64, Ordering::Relaxed);
+                        progress = true;
+                        if self.pos == self.cap {
+                            self.pos = 0;
+                            self.cap = 0;
+                        }
+                    },
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                    Err(e) => return Err(e)
+                }
+            }
+
+            if let Some(ref mut class) = self.class {
+                class.set_holding(self.pos < self.cap);
             }
 
-            // TODO: This exact logic for reading/writing amounts may need an
-            //       update
-            //
-            // Right now the `buffer` is actually pretty big, 64k, and it could
-            // be the case that one end of the connection can far outpace
-            // another. For example we may be able to always read 64k from the
-            // read half but only be able to write 5k to the client. This is a
-            // pretty bad situation because we've got data in a buffer that's
-            // intended to be ephemeral!
-            //
-            // Ideally here we'd actually adapt the rate of reads to match the
-            // rate of writes. That is, we'd prefer to have some form of
-            // adaptive algorithm which keeps track of how many bytes are
-            // written and match the read rate to the write rate. It's possible
-            // for connections to have an even smaller (and optional) buffer on
-            // the side representing the "too much data they read" if that
-            // happens, and then the next call to `read` could compensate by not
-            // reading so much again.
-            //
-            // In any case, though, this is easily implementable in terms of
-            // adding fields to `Transfer` and is complicated enough to
-            // otherwise detract from the example in question here. As a result,
-            // we simply read into the global buffer and then assert that we
-            // write out exactly the same amount.
-            //
-            // This means that we may trip the assert below, but it should be
-            // relatively easily fixable with the strategy above!
-
-            let n = try_nb!((&*self.reader).read(&mut buffer));
-            if n == 0 {
+            if self.read_done && self.pos == self.cap {
                 try!(self.writer.shutdown(Shutdown::Write));
                 return Ok(self.amt.into())
             }
-            self.amt += n as u64;
-
-            // Unlike above, we don't handle `WouldBlock` specially, because
-            // that would play into the logic mentioned above (tracking read
-            // rates and write rates), so we just ferry along that error for
-            // now.
-            let m = try!((&*self.writer).write(&buffer[..n]));
-            assert_eq!(n, m);
+
+            if !progress {
+                // Yield to the other streams and continue in the next round
+                if self.class.as_ref().is_some_and(|c| c.exhausted()) {
+                    task::current().notify();
+                }
+                return Ok(Async::NotReady)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::{IpAddr, TcpStream as StdTcpStream};
+    use futures::Stream;
+    use futures::future::{self, Either};
+    use tokio_core::net::TcpListener;
+    use tokio_core::reactor::Core;
+    use ratelimit::{RateLimits, RateLimiters};
+
+    // Connected pair of sockets, the first one is a blocking std socket
+    fn pair(core: &mut Core) -> (StdTcpStream, TcpStream) {
+        let handle = core.handle();
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+        let client = StdTcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = core.run(listener.incoming().into_future().map_err(|(e, _)| e))
+                              .unwrap().0.unwrap();
+        (client, server)
+    }
+
+    #[test]
+    fn waits_again_if_shared_bucket_is_drained_meanwhile() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let mut limits = RateLimits::default();
+        limits.read_entry("Global", "8").unwrap();
+        let limiters = RateLimiters::new(&limits);
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let limiter = limiters.limiter(Some(ip("10.0.0.1")), None).unwrap();
+        let other = limiters.limiter(Some(ip("10.0.0.2")), None).unwrap();
+        limiter.consume(limiter.available());
+
+        let (mut source, reader) = pair(&mut core);
+        let (_sink, writer) = pair(&mut core);
+        source.write_all(b"hello").unwrap();
+        source.shutdown(Shutdown::Write).unwrap();
+        let mut transfer = Transfer::shaped(Rc::new(reader), Rc::new(writer), &handle,
+                                            Counter::default(), Some(limiter), None);
+
+        // The other connection takes the tokens, after the transfer has
+        // started to wait for them
+        let mut other = Some(other);
+        let relay = future::poll_fn(move || {
+            let res = transfer.poll();
+            if let Some(other) = other.take() {
+                other.consume(1024);
+            }
+            res
+        });
+        let timeout = Timeout::new(Duration::from_secs(5), &handle).unwrap();
+        match core.run(timeout.select2(relay)) {
+            Ok(Either::A(_)) => panic!("transfer is stuck"),
+            Ok(Either::B((amt, _))) => assert_eq!(amt, 5),
+            Err(_) => panic!("transfer failed")
         }
     }
-}
\ No newline at end of file
+}